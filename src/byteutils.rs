@@ -36,6 +36,16 @@ pub fn join_high8(val: Word, high: Byte) -> Word {
     join8(low8(val), high)
 }
 
+/// Sign-extend a Byte to a Word, e.g. sign_extend8(0xFE) = 0xFFFE
+pub fn sign_extend8(val: Byte) -> Word {
+    let word = val.to_u16().unwrap();
+    if val & 0x80 != 0 {
+        word | 0xFF00
+    } else {
+        word
+    }
+}
+
 fn add_overflow(l_sign: bool, r_sign: bool, result_sign: bool) -> bool {
     (result_sign != l_sign) && (l_sign == r_sign)
 }
@@ -49,6 +59,21 @@ fn and_or_overflow(_: bool, _: bool, _: bool) -> bool {
     false
 }
 
+/// Auxiliary carry: a carry out of, or borrow into, bit 3
+fn add_sub_aux(left: Word, right: Word, result: Word) -> bool {
+    (left ^ right ^ result) & 0x10 != 0
+}
+
+fn and_or_aux(_: Word, _: Word, _: Word) -> bool {
+    false
+}
+
+/// Parity flag: true if the low 8 bits of `val' have an even number of
+/// bits set
+pub fn parity(val: Word) -> bool {
+    (val & 0xFF).count_ones() % 2 == 0
+}
+
 fn checked_or<T: Int>(left: T, right: T) -> Option<T> {
     Some(left | right)
 }
@@ -57,19 +82,39 @@ fn checked_and<T: Int>(left: T, right: T) -> Option<T> {
     Some(left & right)
 }
 
+/// Fold the incoming carry of ADC into a partial result
+fn add_carry<T: Int>(partial: T, carry: T) -> T {
+    partial + carry
+}
+
+/// Fold the incoming borrow of SBB into a partial result
+fn sub_carry<T: Int>(partial: T, borrow: T) -> T {
+    partial - borrow
+}
+
+/// The logical operations take no carry
+fn no_carry<T: Int>(partial: T, _: T) -> T {
+    partial
+}
+
 /// Arithmetic functions. Functions generated from this macro take input
-/// as Bytes or Words, calculate the output, and also compute the carry,
-/// overflow, sign, and zero flags.
+/// as Bytes or Words, plus an incoming carry for ADC and SBB, calculate
+/// the output, and also compute the carry, overflow, sign, zero,
+/// auxiliary carry, and parity flags. `$carry_fn' folds the incoming
+/// carry into the result; it is ignored by the logical operations.
 macro_rules! arithmetic (
     (
         $name:ident,
         $input_type:ident,
         $un_op:ident $ch_op:expr,
-        $overflow_fn:ident
+        $carry_fn:ident,
+        $overflow_fn:ident,
+        $aux_fn:ident
     ) => {
-        pub fn $name(left: $input_type, right: $input_type)
-        -> ($input_type, bool, bool, bool, bool) {
-            let result = left.$un_op(right);
+        pub fn $name(left: $input_type, right: $input_type, carry_in: bool)
+        -> ($input_type, bool, bool, bool, bool, bool, bool) {
+            let carry_val: $input_type = if carry_in { 1 } else { 0 };
+            let result = $carry_fn(left.$un_op(right), carry_val);
 
             let l_sign: bool = left.leading_zeros() == 0;
             let r_sign: bool = right.leading_zeros() == 0;
@@ -78,20 +123,102 @@ macro_rules! arithmetic (
             let overflow: bool = $overflow_fn(l_sign, r_sign, result_sign);
             let zero: bool = result == 0;
             let carry: bool = match $ch_op(left, right) {
-                Some(_) => false,
+                Some(partial) => $ch_op(partial, carry_val).is_none(),
                 None => true,
             };
 
-            (result, carry, overflow, result_sign, zero)
+            let left16 = left.to_u16().unwrap();
+            let right16 = right.to_u16().unwrap();
+            let result16 = result.to_u16().unwrap();
+            let aux: bool = $aux_fn(left16, right16, result16);
+            let parity: bool = parity(result16);
+
+            (result, carry, overflow, result_sign, zero, aux, parity)
         }
     }
 );
 
-arithmetic!(add8,  Byte, add Int::checked_add, add_overflow);
-arithmetic!(add16, Word, add Int::checked_add, add_overflow);
-arithmetic!(sub8,  Byte, sub Int::checked_sub, sub_overflow);
-arithmetic!(sub16, Word, sub Int::checked_sub, sub_overflow);
-arithmetic!(or8,   Byte, bitor checked_or,     and_or_overflow);
-arithmetic!(or16,  Word, bitor checked_or,     and_or_overflow);
-arithmetic!(xor16, Word, bitxor checked_or,    and_or_overflow);
-arithmetic!(and16, Word, bitand checked_and,   and_or_overflow);
+arithmetic!(add8,  Byte, add Int::checked_add, add_carry, add_overflow, add_sub_aux);
+arithmetic!(add16, Word, add Int::checked_add, add_carry, add_overflow, add_sub_aux);
+arithmetic!(sub8,  Byte, sub Int::checked_sub, sub_carry, sub_overflow, add_sub_aux);
+arithmetic!(sub16, Word, sub Int::checked_sub, sub_carry, sub_overflow, add_sub_aux);
+arithmetic!(or8,   Byte, bitor checked_or,     no_carry,  and_or_overflow, and_or_aux);
+arithmetic!(or16,  Word, bitor checked_or,     no_carry,  and_or_overflow, and_or_aux);
+arithmetic!(xor8,  Byte, bitxor checked_or,    no_carry,  and_or_overflow, and_or_aux);
+arithmetic!(xor16, Word, bitxor checked_or,    no_carry,  and_or_overflow, and_or_aux);
+arithmetic!(and8,  Byte, bitand checked_and,   no_carry,  and_or_overflow, and_or_aux);
+arithmetic!(and16, Word, bitand checked_and,   no_carry,  and_or_overflow, and_or_aux);
+
+/// Shift and rotate operations, numbered as in the ModR/M reg field of
+/// the D0-D3 group. Setmo is the undocumented /6 encoding, which on the
+/// 8086 sets the operand to all ones.
+pub enum ShiftOp {
+    Rol, Ror, Rcl, Rcr,
+    Shl, Shr, Setmo, Sar,
+}
+
+fn bit_value(bit: bool) -> Word {
+    if bit { 1 } else { 0 }
+}
+
+/// Shift or rotate `val', which is `bits' wide, by `count' positions,
+/// and return the result with the new carry and overflow flags. The count
+/// is not masked, as on the 8086, so every one of up to 255 steps is
+/// applied. A count of 0 leaves everything unchanged.
+pub fn shift(op: ShiftOp, val: Word, count: Word, bits: uint,
+             carry: bool, overflow: bool) -> (Word, bool, bool) {
+    let msb: Word = 1 << (bits - 1);
+    let mask: Word = msb | (msb - 1);
+
+    let mut val = val;
+    let mut carry = carry;
+    let mut overflow = overflow;
+    for _ in range(0, count) {
+        match op {
+            ShiftOp::Rol => {
+                carry = val & msb != 0;
+                val = ((val << 1) & mask) | bit_value(carry);
+                overflow = (val & msb != 0) != carry;
+            },
+            ShiftOp::Ror => {
+                carry = val & 1 != 0;
+                val = (val >> 1) | (bit_value(carry) << (bits - 1));
+                overflow = (val & msb != 0) != (val & (msb >> 1) != 0);
+            },
+            ShiftOp::Rcl => {
+                let carry_out = val & msb != 0;
+                val = ((val << 1) & mask) | bit_value(carry);
+                carry = carry_out;
+                overflow = (val & msb != 0) != carry;
+            },
+            ShiftOp::Rcr => {
+                let carry_out = val & 1 != 0;
+                val = (val >> 1) | (bit_value(carry) << (bits - 1));
+                carry = carry_out;
+                overflow = (val & msb != 0) != (val & (msb >> 1) != 0);
+            },
+            ShiftOp::Shl => {
+                carry = val & msb != 0;
+                val = (val << 1) & mask;
+                overflow = (val & msb != 0) != carry;
+            },
+            ShiftOp::Shr => {
+                overflow = val & msb != 0;
+                carry = val & 1 != 0;
+                val = val >> 1;
+            },
+            ShiftOp::Setmo => {
+                carry = false;
+                overflow = false;
+                val = mask;
+            },
+            ShiftOp::Sar => {
+                carry = val & 1 != 0;
+                val = (val >> 1) | (val & msb);
+                overflow = false;
+            },
+        }
+    }
+
+    (val, carry, overflow)
+}
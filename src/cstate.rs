@@ -3,8 +3,11 @@ use std::io::File;
 use std::num::ToPrimitive;
 use self::Reg8::*;
 use self::Reg16::*;
+use self::SegReg::*;
 use byteutils::{low8, high8, join8, join_low8, join_high8};
 use datatypes::{Byte, Word};
+use fpu::Fpu;
+use iobus::{IoBus, PortMap};
 use operand::Flags;
 
 
@@ -21,9 +24,56 @@ pub enum Reg8 {
     DH, DL,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum SegReg {
+    ES, CS, SS, DS,
+}
+
+/// Repeat prefixes. 0xF3 is REP/REPE/REPZ, 0xF2 is REPNE/REPNZ. The V20
+/// adds 0x65 REPC and 0x64 REPNC, which test CF instead of ZF.
+#[derive(Clone, Copy, PartialEq)]
+pub enum RepPrefix {
+    RepE,
+    RepNE,
+    RepC,
+    RepNC,
+}
+
+pub enum Prefix {
+    Segment(SegReg),
+    Lock,
+    Rep(RepPrefix),
+}
+
+/// How to decode undocumented encodings. Accurate mode executes them as
+/// real 8086 silicon does; strict mode reports them as invalid opcodes,
+/// which stops emulation on a model without the invalid opcode trap.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DecodeMode {
+    Accurate,
+    Strict,
+}
+
+/// The processor being emulated. The 80186 and 80188 differ only in bus
+/// width, so they share a model, as do the NEC V20 and V30.
+#[derive(Clone, Copy, PartialEq)]
+pub enum CpuModel {
+    I8086,
+    I80186,
+    V20,
+}
+
+/// Size of the 8086's 20-bit physical address space
+pub const MEMORY_SIZE: uint = 0x100000;
+
 pub struct CpuState {
     _state: Vec<u8>,
 
+    es: u16, // Extra segment
+    cs: u16, // Code segment
+    ss: u16, // Stack segment
+    ds: u16, // Data segment
+
     ax: u16,
     bx: u16,
     cx: u16,
@@ -40,16 +90,48 @@ pub struct CpuState {
     of: bool, // Overflow flag
     sf: bool, // Sign flag
     zf: bool, // Zero flag
+    af: bool, // Auxiliary carry flag
+    pf: bool, // Parity flag
+    df: bool, // Direction flag
+    intf: bool, // Interrupt enable flag
+    tf: bool, // Trap flag
+
+    seg_override: Option<SegReg>, // Segment override prefix
+    rep: Option<RepPrefix>, // Repeat prefix
+    lock: bool, // LOCK prefix
+    prefix_ip: Word, // Offset of the most recent prefix byte
+    instruction_ip: Word, // Offset of the current instruction's first byte
+    prefixes_latched: bool, // Keep prefixes for the next instruction
+    last_address: (SegReg, Word), // Most recent effective address decoded
+
+    pending_irqs: Vec<Byte>, // Hardware interrupt vectors awaiting service
+    interrupt_shadow: bool, // Don't recognise interrupts after this instruction
+    nmi_pending: bool, // Non-maskable interrupt latched
+    single_step: bool, // TF was set when this instruction began
+    halted: bool, // Waiting for an interrupt after HLT
+    invalid_opcode: Option<Byte>, // Opcode that stopped emulation
+    exit_on_halt: bool, // HLT with IF clear ends emulation
+    decode_mode: DecodeMode, // Treatment of undocumented opcodes
+    model: CpuModel, // Instruction set to decode
+    emulation_mode: bool, // V20 running 8080 code (MD flag clear)
+
+    io: Box<IoBus + 'static>, // Port I/O bus
+    fpu: Fpu, // 8087 coprocessor
 }
 
 impl CpuState {
     pub fn new() -> CpuState {
         let mut mem = Vec::new();
-        mem.resize(65535, 0u8);
+        mem.resize(MEMORY_SIZE, 0u8);
 
         CpuState {
             _state: mem,
 
+            es: 0,
+            cs: 0,
+            ss: 0,
+            ds: 0,
+
             ax: 0,
             bx: 0,
             cx: 0,
@@ -64,30 +146,140 @@ impl CpuState {
             of: false,
             sf: false,
             zf: false,
+            af: false,
+            pf: false,
+            df: false,
+            intf: false,
+            tf: false,
+
+            seg_override: None,
+            rep: None,
+            lock: false,
+            prefix_ip: 0,
+            instruction_ip: 0,
+            prefixes_latched: false,
+            last_address: (SegReg::DS, 0),
+
+            pending_irqs: Vec::new(),
+            interrupt_shadow: false,
+            nmi_pending: false,
+            single_step: false,
+            halted: false,
+            invalid_opcode: None,
+            exit_on_halt: true,
+            decode_mode: DecodeMode::Accurate,
+            model: CpuModel::I8086,
+            emulation_mode: false,
+
+            io: Box::new(PortMap::new()),
+            fpu: Fpu::new(),
         }
     }
         
+    /// Load a flat binary at physical address 0. All segment registers
+    /// start at 0, so the program's offsets are also its physical addresses.
     pub fn load_program(&mut self, path: &Path) {
         let prog = File::open(path).read_to_end().unwrap();
-        for byte in prog.iter().rev() {
-            self._state.pop();
-            self._state.insert(0, *byte);
+        for (i, byte) in prog.iter().enumerate() {
+            self._state[i] = *byte;
         }
     }
 
-    pub fn getmem(&self, i: Word) -> Byte {
-        let idx = i.to_uint().unwrap();
+    /// Translate seg:offset to a 20-bit physical address. Addresses past
+    /// the top of memory wrap around to 0, as on a real 8086.
+    pub fn physical_address(seg: Word, offset: Word) -> uint {
+        let seg = seg.to_uint().unwrap();
+        let offset = offset.to_uint().unwrap();
+        ((seg << 4) + offset) % MEMORY_SIZE
+    }
+
+    pub fn getmem(&self, seg: &SegReg, offset: Word) -> Byte {
+        let idx = CpuState::physical_address(self.getseg(seg), offset);
         let val = self._state[idx];
         let val = val.to_u8().unwrap();
         val
     }
 
-    pub fn setmem(&mut self, addr: Word, val: Byte) {
-        let idx = addr.to_uint().unwrap();
+    pub fn setmem(&mut self, seg: &SegReg, offset: Word, val: Byte) {
+        let idx = CpuState::physical_address(self.getseg(seg), offset);
         let val8 = val.to_u8().unwrap();
         self._state[idx] = val8
     }
 
+    /// Read a little-endian Word from seg:offset. The second byte wraps
+    /// around within the segment if offset is 0xFFFF.
+    pub fn getmem16(&self, seg: &SegReg, offset: Word) -> Word {
+        join8(self.getmem(seg, offset + 1), self.getmem(seg, offset))
+    }
+
+    /// Write a little-endian Word to seg:offset
+    pub fn setmem16(&mut self, seg: &SegReg, offset: Word, val: Word) {
+        self.setmem(seg, offset, high8(val));
+        self.setmem(seg, offset + 1, low8(val));
+    }
+
+    /// Replace the I/O bus, e.g. with one that devices are attached to
+    pub fn set_io_bus(&mut self, bus: Box<IoBus + 'static>) {
+        self.io = bus;
+    }
+
+    pub fn io_bus(&mut self) -> &mut IoBus {
+        &mut *self.io
+    }
+
+    pub fn port_in8(&mut self, port: Word) -> Byte {
+        self.io.port_in(port)
+    }
+
+    pub fn port_out8(&mut self, port: Word, val: Byte) {
+        self.io.port_out(port, val);
+    }
+
+    /// Read a Word from `port' and `port' + 1. An odd port is read as two
+    /// byte accesses, low port first.
+    pub fn port_in16(&mut self, port: Word) -> Word {
+        if port & 1 == 0 {
+            return self.io.port_in16(port);
+        }
+        let high_b = self.io.port_in(port);
+        let low_b = self.io.port_in(port + 1);
+        join8(low_b, high_b)
+    }
+
+    /// Write a Word to `port' and `port' + 1. An odd port is written as
+    /// two byte accesses, low port first.
+    pub fn port_out16(&mut self, port: Word, val: Word) {
+        if port & 1 == 0 {
+            return self.io.port_out16(port, val);
+        }
+        self.io.port_out(port, high8(val));
+        self.io.port_out(port + 1, low8(val));
+    }
+
+    /// The 8087 coprocessor attached to this CPU
+    pub fn fpu(&mut self) -> &mut Fpu {
+        &mut self.fpu
+    }
+
+    /// Get the current value of the specified segment register.
+    pub fn getseg(&self, reg: &SegReg) -> Word {
+        match *reg {
+            ES => return self.es,
+            CS => return self.cs,
+            SS => return self.ss,
+            DS => return self.ds,
+        }
+    }
+
+    /// Set the current value of the specified segment register.
+    pub fn setseg(&mut self, reg: &SegReg, new_val: Word) {
+        match *reg {
+            ES => self.es = new_val,
+            CS => self.cs = new_val,
+            SS => self.ss = new_val,
+            DS => self.ds = new_val,
+        }
+    }
     
     /// Get the current value of the specified 16-bit register.
     pub fn getreg16(&self, reg: &Reg16) -> Word {
@@ -147,15 +339,15 @@ impl CpuState {
         }
     }
 
-    /// Read a Byte from the memory location at `ip` and advance `ip`.
+    /// Read a Byte from the memory location at CS:IP and advance `ip`.
     pub fn read(&mut self) -> Byte {
-        let byte: Byte = self.getmem(self.ip);
+        let byte: Byte = self.getmem(&CS, self.ip);
         self.ip += 1;
 
         byte
     }
     
-    /// Read a Word from the memory location at `ip` and advance `ip`.
+    /// Read a Word from the memory location at CS:IP and advance `ip`.
     pub fn read16(&mut self) -> Word {
         let high_b: Byte = self.read();
         let low_b: Byte = self.read();
@@ -164,20 +356,248 @@ impl CpuState {
         word
     }
 
+    /// Called before decoding each instruction. Forgets the previous
+    /// instruction's prefixes, unless they were latched by
+    /// `repeat_instruction'.
+    pub fn begin_instruction(&mut self) {
+        self.instruction_ip = self.ip - 1;
+        self.single_step = self.tf;
+        if !self.prefixes_latched {
+            self.clear_prefixes();
+        }
+        self.prefixes_latched = false;
+    }
+
+    /// Offset of the current instruction's first byte, including its
+    /// prefixes
+    pub fn instruction_ip(&self) -> Word {
+        self.instruction_ip
+    }
+
+    pub fn clear_prefixes(&mut self) {
+        self.seg_override = None;
+        self.rep = None;
+        self.lock = false;
+        self.prefixes_latched = false;
+    }
+
+    /// Record a prefix byte that was just read from CS:IP.
+    pub fn add_prefix(&mut self, prefix: Prefix) {
+        match prefix {
+            Prefix::Segment(seg) => self.seg_override = Some(seg),
+            Prefix::Lock => self.lock = true,
+            Prefix::Rep(rep) => self.rep = Some(rep),
+        }
+        self.prefix_ip = self.ip - 1;
+    }
+
+    /// Run the current instruction again, e.g. for the next iteration of a
+    /// REP string operation.
+    ///
+    /// Like the 8086, this only backs IP up to the last prefix byte and
+    /// keeps the other prefixes latched internally. If an interrupt is
+    /// taken before the instruction resumes, the latched prefixes are lost,
+    /// so on return only the last prefix is still in effect: `rep es movsb'
+    /// comes back as `es movsb'.
+    pub fn repeat_instruction(&mut self) {
+        self.ip = self.prefix_ip;
+        self.prefixes_latched = true;
+    }
+
+    /// Back IP up to the start of the current instruction, including its
+    /// prefixes, so that an exception returns to the faulting instruction.
+    pub fn restart_instruction(&mut self) {
+        self.ip = self.instruction_ip;
+    }
+
+    /// Record the effective address of a memory operand as it is decoded
+    pub fn set_last_address(&mut self, seg: SegReg, addr: Word) {
+        self.last_address = (seg, addr);
+    }
+
+    /// The effective address of the most recently decoded memory operand.
+    /// The 8086 substitutes it when an instruction that needs a memory
+    /// operand is given a register.
+    pub fn last_address(&self) -> (SegReg, Word) {
+        self.last_address
+    }
+
+    /// Return the segment to use for a memory access whose default segment
+    /// is `default', taking any segment override prefix into account.
+    pub fn segment(&self, default: SegReg) -> SegReg {
+        match self.seg_override {
+            Some(seg) => seg,
+            None => default,
+        }
+    }
+
+    pub fn rep_prefix(&self) -> Option<RepPrefix> {
+        self.rep
+    }
+
+    pub fn lock(&self) -> bool {
+        self.lock
+    }
+
+    /// Transfer control to the handler for interrupt `vector': push FLAGS,
+    /// CS and IP, clear IF and TF, and load CS:IP from the interrupt vector
+    /// table at 0000:0000. A V20 in 8080 emulation mode switches back to
+    /// native mode; the pushed FLAGS records the mode to return to.
+    pub fn interrupt(&mut self, vector: Byte) {
+        let flags = self.get_flags_word();
+        let cs = self.cs;
+        let ip = self.ip;
+        self.push(flags);
+        self.push(cs);
+        self.push(ip);
+
+        self.intf = false;
+        self.tf = false;
+        self.halted = false;
+        self.emulation_mode = false;
+        self.clear_prefixes();
+
+        let entry = vector.to_uint().unwrap() * 4;
+        self.ip = join8(self._state[entry + 1], self._state[entry]);
+        self.cs = join8(self._state[entry + 3], self._state[entry + 2]);
+    }
+
+    /// Raise a maskable hardware interrupt with the given vector. It is
+    /// serviced by `service_interrupts' once IF is set.
+    pub fn request_interrupt(&mut self, vector: Byte) {
+        self.pending_irqs.push(vector);
+    }
+
+    /// Assert the non-maskable interrupt input. NMI is latched and serviced
+    /// at the next instruction boundary regardless of IF.
+    pub fn raise_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// True if an interrupt would be dispatched at the next instruction
+    /// boundary
+    pub fn interrupt_pending(&self) -> bool {
+        self.nmi_pending || (self.intf && !self.pending_irqs.is_empty())
+    }
+
+    /// Stop executing instructions until an interrupt or NMI is dispatched
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Stop emulation at the current instruction, whose opcode the CPU
+    /// has no way to report as invalid. IP is left pointing at the
+    /// instruction, including its prefixes.
+    pub fn stop_invalid_opcode(&mut self, opcode: Byte) {
+        self.ip = self.instruction_ip;
+        self.invalid_opcode = Some(opcode);
+    }
+
+    /// The opcode that stopped emulation, if any
+    pub fn invalid_opcode(&self) -> Option<Byte> {
+        self.invalid_opcode
+    }
+
+    /// Choose whether HLT with IF clear ends emulation. Such a CPU can only
+    /// be woken by NMI, so programs like codegolf.asm use it to exit.
+    pub fn set_exit_on_halt(&mut self, exit: bool) {
+        self.exit_on_halt = exit;
+    }
+
+    pub fn exit_on_halt(&self) -> bool {
+        self.exit_on_halt
+    }
+
+    pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+        self.decode_mode = mode;
+    }
+
+    pub fn decode_mode(&self) -> DecodeMode {
+        self.decode_mode
+    }
+
+    pub fn set_model(&mut self, model: CpuModel) {
+        self.model = model;
+    }
+
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
+    /// Whether a V20 is executing 8080 instructions. This is the inverse
+    /// of the MD flag.
+    pub fn emulation_mode(&self) -> bool {
+        self.emulation_mode
+    }
+
+    pub fn set_emulation_mode(&mut self, emulation: bool) {
+        self.emulation_mode = emulation;
+    }
+
+    /// Hold off interrupts, including NMI and the single-step trap, until
+    /// after the next instruction, as the 8086 does after STI and loads of
+    /// SS.
+    pub fn inhibit_interrupts(&mut self) {
+        self.interrupt_shadow = true;
+    }
+
+    /// Called between instructions to recognise interrupts. NMI takes
+    /// priority over maskable hardware interrupts, which need IF set.
+    ///
+    /// The single-step trap (INT 1) is taken after an instruction that
+    /// began with TF set, so it first fires after the instruction following
+    /// the POPF or IRET that set TF. If another interrupt is dispatched
+    /// while TF is set, the 8086 takes the trap immediately afterwards, so
+    /// the trap handler runs first and returns to the start of the other
+    /// handler.
+    pub fn service_interrupts(&mut self) {
+        let single_step = self.single_step;
+        self.single_step = false;
+
+        if self.interrupt_shadow {
+            self.interrupt_shadow = false;
+            return;
+        }
+
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            Some(2)
+        } else if self.intf && !self.pending_irqs.is_empty() {
+            Some(self.pending_irqs.remove(0))
+        } else {
+            None
+        };
+
+        let trap = match vector {
+            Some(vector) => {
+                let trap = self.tf;
+                self.interrupt(vector);
+                trap
+            },
+            None => single_step,
+        };
+
+        if trap {
+            self.interrupt(1);
+        }
+    }
+
+    /// Push a Word onto the stack at SS:SP
     pub fn push(&mut self, val: Word) {
-        let low_b = low8(val);
-        let high_b = high8(val);
-        let sp = self.sp;
-        self.setmem(sp - 2, high_b);
-        self.setmem(sp - 1, low_b);
-        self.sp = sp - 2;
+        let sp = self.sp - 2;
+        self.setmem16(&SS, sp, val);
+        self.sp = sp;
     }
 
+    /// Pop a Word from the stack at SS:SP
     pub fn pop(&mut self) -> Word {
-        let low_b = self.getmem(self.sp + 1);
-        let high_b = self.getmem(self.sp);
+        let val = self.getmem16(&SS, self.sp);
         self.sp = self.sp + 2;
-        join8(low_b, high_b)
+        val
     }
 
     pub fn set_flags(&mut self, f: Flags) {
@@ -185,6 +605,8 @@ impl CpuState {
         self.of = f.overflow;
         self.sf = f.sign;
         self.zf = f.zero;
+        self.af = f.aux_carry;
+        self.pf = f.parity;
     }
 
     pub fn get_flags(&self) -> Flags {
@@ -193,6 +615,70 @@ impl CpuState {
             overflow: self.of,
             sign: self.sf,
             zero: self.zf,
+            aux_carry: self.af,
+            parity: self.pf,
+        }
+    }
+
+    /// Pack all flags into the 16-bit FLAGS register. On the 8086, bits
+    /// 12-15 and bit 1 always read as 1, and bits 3 and 5 as 0. On the V20
+    /// bit 15 is the MD flag, which is clear in 8080 emulation mode.
+    pub fn get_flags_word(&self) -> Word {
+        let bits = [
+            (self.cf, 0u),
+            (self.pf, 2u),
+            (self.af, 4u),
+            (self.zf, 6u),
+            (self.sf, 7u),
+            (self.tf, 8u),
+            (self.intf, 9u),
+            (self.df, 10u),
+            (self.of, 11u),
+        ];
+
+        let mut word: Word = if self.emulation_mode { 0x7002 } else { 0xF002 };
+        for &(flag, bit) in bits.iter() {
+            if flag {
+                word = word | (1 << bit);
+            }
+        }
+        word
+    }
+
+    /// Unpack a 16-bit FLAGS value into the individual flags. The fixed
+    /// bits are ignored.
+    pub fn set_flags_word(&mut self, word: Word) {
+        self.cf = flag_bit(word, 0);
+        self.pf = flag_bit(word, 2);
+        self.af = flag_bit(word, 4);
+        self.zf = flag_bit(word, 6);
+        self.sf = flag_bit(word, 7);
+        self.tf = flag_bit(word, 8);
+        self.intf = flag_bit(word, 9);
+        self.df = flag_bit(word, 10);
+        self.of = flag_bit(word, 11);
+    }
+
+    /// Evaluate one of the sixteen 8086 condition codes, numbered as in
+    /// the low nibble of the Jcc opcodes 0x70-0x7F. Odd codes are the
+    /// negation of the even code before them.
+    pub fn condition(&self, cc: Byte) -> bool {
+        let result = match cc >> 1 {
+            0b000 => self.of, // O
+            0b001 => self.cf, // B
+            0b010 => self.zf, // Z
+            0b011 => self.cf || self.zf, // BE
+            0b100 => self.sf, // S
+            0b101 => self.pf, // P
+            0b110 => self.sf != self.of, // L
+            0b111 => (self.sf != self.of) || self.zf, // LE
+            _ => panic!("Invalid condition code"),
+        };
+
+        if cc & 1 == 1 {
+            !result
+        } else {
+            result
         }
     }
 
@@ -211,4 +697,32 @@ impl CpuState {
     pub fn set_carry(&mut self) {
         self.cf = true;
     }
+
+    pub fn direction(&self) -> bool {
+        self.df
+    }
+
+    pub fn set_direction(&mut self, df: bool) {
+        self.df = df;
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.intf
+    }
+
+    pub fn set_interrupts_enabled(&mut self, intf: bool) {
+        self.intf = intf;
+    }
+
+    pub fn trap(&self) -> bool {
+        self.tf
+    }
+
+    pub fn set_trap(&mut self, tf: bool) {
+        self.tf = tf;
+    }
+}
+
+fn flag_bit(word: Word, bit: uint) -> bool {
+    (word >> bit) & 1 == 1
 }
@@ -1,4 +1,4 @@
-use cstate::{CpuState, Reg16, Reg8};
+use cstate::{CpuState, Reg16, Reg8, SegReg};
 use datatypes::{Byte, Word};
 
 
@@ -29,7 +29,7 @@ fn dump_mem(cs: &CpuState, start: Word) {
     let mut s_hex = String::new();
     let mut s_chr = String::new();
     for i in range(0, 16) {
-        let val: Byte = cs.getmem(start+i);
+        let val: Byte = cs.getmem(&SegReg::DS, start+i);
         s_hex.push_str(format!("{:0>2X} ", val).as_slice());
         s_chr.push_str(format!("{:}", val as char).as_slice());
     }
@@ -44,7 +44,7 @@ pub fn dump_vram(cs: &CpuState) {
     for row in range(0, rowcnt) {
         for col in range(0, rowlen) {
             let offset = (row * rowlen) + col;
-            let val = cs.getmem(start + offset);
+            let val = cs.getmem(&SegReg::DS, start + offset);
             let val = match val {
                 0 => ' ',
                 _ => val as char,
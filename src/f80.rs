@@ -0,0 +1,1443 @@
+use std::cmp::Ordering;
+use std::num::Int;
+use std::i32;
+use datatypes::{Byte, Word};
+
+
+/// Exception flags, laid out as in the low bits of the 8087 status and
+/// control words
+pub const INVALID: Word = 0x01;
+pub const DENORMAL: Word = 0x02;
+pub const ZERO_DIVIDE: Word = 0x04;
+pub const OVERFLOW: Word = 0x08;
+pub const UNDERFLOW: Word = 0x10;
+pub const PRECISION: Word = 0x20;
+
+const BIAS: i32 = 16383;
+const MAX_EXP: i32 = 0x7FFF;
+const INTEGER_BIT: u64 = 1 << 63;
+const QUIET_BIT: u64 = 1 << 62;
+
+
+/// An 80-bit extended precision value, stored as on the 8087: a sign, a
+/// 15-bit biased exponent, and a 64-bit significand with an explicit
+/// integer bit. All arithmetic is done in software on these fields.
+#[derive(Clone, Copy)]
+pub struct F80 {
+    pub sign: bool,
+    pub exponent: u16,
+    pub mantissa: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Class {
+    Zero,
+    Denormal,
+    Unnormal,
+    Normal,
+    Infinity,
+    NaN,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Rounding {
+    Nearest,
+    Down,
+    Up,
+    Chop,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    Equal,
+    Greater,
+    Unordered,
+}
+
+/// The rounding mode and precision (24, 53 or 64 bits) that arithmetic
+/// results are rounded to
+#[derive(Clone, Copy)]
+pub struct Env {
+    pub rounding: Rounding,
+    pub precision: uint,
+}
+
+/// A binary floating point format: significand precision, including the
+/// integer bit, and exponent width and bias
+#[derive(Clone, Copy)]
+pub struct Format {
+    pub precision: uint,
+    pub exponent_bits: uint,
+    pub bias: i32,
+    pub max_exp: i32,
+}
+
+pub const SINGLE: Format = Format {
+    precision: 24,
+    exponent_bits: 8,
+    bias: 127,
+    max_exp: 0xFF,
+};
+
+pub const DOUBLE: Format = Format {
+    precision: 53,
+    exponent_bits: 11,
+    bias: 1023,
+    max_exp: 0x7FF,
+};
+
+/// Environment for intermediate results of the transcendental functions
+const INTERNAL: Env = Env {
+    rounding: Rounding::Nearest,
+    precision: 64,
+};
+
+pub const ONE: F80 = F80 { sign: false, exponent: 0x3FFF, mantissa: 0x8000000000000000 };
+pub const L2T: F80 = F80 { sign: false, exponent: 0x4000, mantissa: 0xD49A784BCD1B8AFE };
+pub const L2E: F80 = F80 { sign: false, exponent: 0x3FFF, mantissa: 0xB8AA3B295C17F0BC };
+pub const PI: F80 = F80 { sign: false, exponent: 0x4000, mantissa: 0xC90FDAA22168C235 };
+pub const LG2: F80 = F80 { sign: false, exponent: 0x3FFD, mantissa: 0x9A209A84FBCFF799 };
+pub const LN2: F80 = F80 { sign: false, exponent: 0x3FFE, mantissa: 0xB17217F7D1CF79AC };
+
+
+impl F80 {
+    pub fn class(&self) -> Class {
+        if self.exponent == 0x7FFF {
+            if self.mantissa << 1 == 0 {
+                Class::Infinity
+            } else {
+                Class::NaN
+            }
+        } else if self.mantissa == 0 {
+            Class::Zero
+        } else if self.exponent == 0 {
+            Class::Denormal
+        } else if self.mantissa & INTEGER_BIT == 0 {
+            Class::Unnormal
+        } else {
+            Class::Normal
+        }
+    }
+
+    pub fn negate(&self) -> F80 {
+        F80 { sign: !self.sign, exponent: self.exponent, mantissa: self.mantissa }
+    }
+
+    pub fn abs(&self) -> F80 {
+        F80 { sign: false, exponent: self.exponent, mantissa: self.mantissa }
+    }
+}
+
+pub fn zero(sign: bool) -> F80 {
+    F80 { sign: sign, exponent: 0, mantissa: 0 }
+}
+
+pub fn infinity(sign: bool) -> F80 {
+    F80 { sign: sign, exponent: 0x7FFF, mantissa: INTEGER_BIT }
+}
+
+/// The "indefinite" quiet NaN returned by masked invalid operations
+pub fn indefinite() -> F80 {
+    F80 { sign: true, exponent: 0x7FFF, mantissa: INTEGER_BIT | QUIET_BIT }
+}
+
+
+// 128-bit helpers. Values are (hi, lo) pairs.
+
+fn shl128(hi: u64, lo: u64, n: uint) -> (u64, u64) {
+    if n == 0 {
+        (hi, lo)
+    } else if n < 64 {
+        ((hi << n) | (lo >> (64 - n)), lo << n)
+    } else if n < 128 {
+        (lo << (n - 64), 0)
+    } else {
+        (0, 0)
+    }
+}
+
+/// Shift right, ORing any bits shifted out into the lowest bit so that
+/// rounding can still tell the result is inexact
+fn shr128_sticky(hi: u64, lo: u64, n: uint) -> (u64, u64) {
+    if n == 0 {
+        (hi, lo)
+    } else if n < 64 {
+        let lost = lo << (64 - n) != 0;
+        (hi >> n, (lo >> n) | (hi << (64 - n)) | sticky(lost))
+    } else if n == 64 {
+        (0, hi | sticky(lo != 0))
+    } else if n < 128 {
+        let lost = lo != 0 || hi << (128 - n) != 0;
+        (0, (hi >> (n - 64)) | sticky(lost))
+    } else {
+        (0, sticky(hi != 0 || lo != 0))
+    }
+}
+
+fn sticky(lost: bool) -> u64 {
+    if lost { 1 } else { 0 }
+}
+
+fn add128(a_hi: u64, a_lo: u64, b_hi: u64, b_lo: u64) -> (u64, u64, bool) {
+    let lo = a_lo + b_lo;
+    let carry_lo = if lo < a_lo { 1 } else { 0 };
+    let hi = a_hi + b_hi;
+    let carry = hi < a_hi;
+    let hi_c = hi + carry_lo;
+    (hi_c, lo, carry || hi_c < hi)
+}
+
+fn sub128(a_hi: u64, a_lo: u64, b_hi: u64, b_lo: u64) -> (u64, u64) {
+    let lo = a_lo - b_lo;
+    let borrow = if a_lo < b_lo { 1 } else { 0 };
+    (a_hi - b_hi - borrow, lo)
+}
+
+fn cmp128(a_hi: u64, a_lo: u64, b_hi: u64, b_lo: u64) -> Ordering {
+    if a_hi != b_hi {
+        a_hi.cmp(&b_hi)
+    } else {
+        a_lo.cmp(&b_lo)
+    }
+}
+
+/// Full 64x64 -> 128-bit multiply
+fn mul64(a: u64, b: u64) -> (u64, u64) {
+    let (a_hi, a_lo) = (a >> 32, a & 0xFFFFFFFF);
+    let (b_hi, b_lo) = (b >> 32, b & 0xFFFFFFFF);
+
+    let ll = a_lo * b_lo;
+    let lh = a_lo * b_hi;
+    let hl = a_hi * b_lo;
+    let hh = a_hi * b_hi;
+
+    let mid = (ll >> 32) + (lh & 0xFFFFFFFF) + (hl & 0xFFFFFFFF);
+    let lo = (ll & 0xFFFFFFFF) | (mid << 32);
+    let hi = hh + (lh >> 32) + (hl >> 32) + (mid >> 32);
+    (hi, lo)
+}
+
+
+/// Split a finite, non-zero value into its sign, the unbiased exponent of
+/// its integer bit, and a normalized significand. Denormals and unnormals
+/// come out normalized.
+fn unpack(x: &F80) -> (bool, i32, u64) {
+    let exp = if x.exponent == 0 {
+        1 - BIAS
+    } else {
+        x.exponent as i32 - BIAS
+    };
+    let shift = x.mantissa.leading_zeros();
+    (x.sign, exp - shift as i32, x.mantissa << shift)
+}
+
+/// Round sign * hi:lo * 2^(exp - 127) to `format' with `rounding'.
+/// Returns the sign, the biased exponent, and the significand with its
+/// integer bit at bit 63. Denormal results have a biased exponent of 0.
+fn round(sign: bool, exp: i32, hi: u64, lo: u64, format: &Format,
+         rounding: Rounding, exc: &mut Word) -> (bool, i32, u64) {
+    if hi == 0 && lo == 0 {
+        return (sign, 0, 0);
+    }
+
+    // Normalize so the integer bit is bit 63 of hi
+    let shift = if hi == 0 {
+        64 + lo.leading_zeros()
+    } else {
+        hi.leading_zeros()
+    };
+    let (mut hi, mut lo) = shl128(hi, lo, shift);
+    let mut biased = exp - shift as i32 + format.bias;
+
+    // Results below the normal range are denormalized before rounding
+    let tiny = biased < 1;
+    if tiny {
+        let (h, l) = shr128_sticky(hi, lo, (1 - biased) as uint);
+        hi = h;
+        lo = l;
+        biased = 1;
+    }
+
+    let drop = 64 - format.precision;
+    let unit: u64 = 1 << drop;
+    let mut kept = hi & !(unit - 1);
+    let (rest_hi, rest_lo) = (hi & (unit - 1), lo);
+    let (half_hi, half_lo) = if drop == 0 {
+        (0, INTEGER_BIT)
+    } else {
+        (1 << (drop - 1), 0)
+    };
+
+    let inexact = rest_hi != 0 || rest_lo != 0;
+    let versus_half = cmp128(rest_hi, rest_lo, half_hi, half_lo);
+    let round_up = match rounding {
+        Rounding::Nearest => {
+            versus_half == Ordering::Greater ||
+            (versus_half == Ordering::Equal && kept & unit != 0)
+        },
+        Rounding::Down => inexact && sign,
+        Rounding::Up => inexact && !sign,
+        Rounding::Chop => false,
+    };
+
+    if round_up {
+        kept = match kept.checked_add(unit) {
+            Some(v) => v,
+            None => {
+                biased = biased + 1;
+                INTEGER_BIT
+            },
+        };
+    }
+
+    if inexact {
+        *exc = *exc | PRECISION;
+        if tiny {
+            *exc = *exc | UNDERFLOW;
+        }
+    }
+
+    if kept & INTEGER_BIT == 0 {
+        biased = 0;
+    }
+
+    if biased >= format.max_exp {
+        *exc = *exc | OVERFLOW | PRECISION;
+        let to_infinity = match rounding {
+            Rounding::Nearest => true,
+            Rounding::Down => sign,
+            Rounding::Up => !sign,
+            Rounding::Chop => false,
+        };
+        return if to_infinity {
+            (sign, format.max_exp, INTEGER_BIT)
+        } else {
+            (sign, format.max_exp - 1, !(unit - 1))
+        };
+    }
+
+    (sign, biased, kept)
+}
+
+/// Round to the extended exponent range at the precision in `env'
+fn round_extended(sign: bool, exp: i32, hi: u64, lo: u64,
+                  env: &Env, exc: &mut Word) -> F80 {
+    let format = Format {
+        precision: env.precision,
+        exponent_bits: 15,
+        bias: BIAS,
+        max_exp: MAX_EXP,
+    };
+    let (sign, biased, mantissa) = round(sign, exp, hi, lo, &format, env.rounding, exc);
+    F80 { sign: sign, exponent: biased as u16, mantissa: mantissa }
+}
+
+/// Round a finite value to the precision in `env'
+fn round_value(x: F80, env: &Env, exc: &mut Word) -> F80 {
+    match x.class() {
+        Class::Zero | Class::Infinity | Class::NaN => x,
+        _ => {
+            let (sign, exp, mantissa) = unpack(&x);
+            round_extended(sign, exp, mantissa, 0, env, exc)
+        },
+    }
+}
+
+fn is_signaling(x: &F80) -> bool {
+    x.class() == Class::NaN && x.mantissa & QUIET_BIT == 0
+}
+
+fn quiet(x: F80) -> F80 {
+    F80 { sign: x.sign, exponent: x.exponent, mantissa: x.mantissa | QUIET_BIT }
+}
+
+/// If either operand is a NaN, return the NaN result: the operand NaN
+/// with the larger significand, made quiet. Signaling NaNs are invalid.
+fn propagate_nan(a: &F80, b: &F80, exc: &mut Word) -> Option<F80> {
+    let a_nan = a.class() == Class::NaN;
+    let b_nan = b.class() == Class::NaN;
+    if !a_nan && !b_nan {
+        return None;
+    }
+
+    if is_signaling(a) || is_signaling(b) {
+        *exc = *exc | INVALID;
+    }
+
+    let nan = if a_nan && b_nan {
+        if a.mantissa | QUIET_BIT >= b.mantissa | QUIET_BIT { *a } else { *b }
+    } else if a_nan {
+        *a
+    } else {
+        *b
+    };
+    Some(quiet(nan))
+}
+
+fn check_denormal(x: &F80, exc: &mut Word) {
+    if x.class() == Class::Denormal {
+        *exc = *exc | DENORMAL;
+    }
+}
+
+
+pub fn add(a: F80, b: F80, env: &Env, exc: &mut Word) -> F80 {
+    add_sub(a, b, env, exc)
+}
+
+pub fn sub(a: F80, b: F80, env: &Env, exc: &mut Word) -> F80 {
+    if b.class() == Class::NaN {
+        return add_sub(a, b, env, exc);
+    }
+    add_sub(a, b.negate(), env, exc)
+}
+
+fn add_sub(a: F80, b: F80, env: &Env, exc: &mut Word) -> F80 {
+    match propagate_nan(&a, &b, exc) {
+        Some(nan) => return nan,
+        None => {},
+    }
+    check_denormal(&a, exc);
+    check_denormal(&b, exc);
+
+    match (a.class(), b.class()) {
+        (Class::Infinity, Class::Infinity) => {
+            if a.sign == b.sign {
+                return a;
+            }
+            *exc = *exc | INVALID;
+            return indefinite();
+        },
+        (Class::Infinity, _) => return a,
+        (_, Class::Infinity) => return b,
+        (Class::Zero, Class::Zero) => {
+            let sign = if a.sign == b.sign {
+                a.sign
+            } else {
+                env.rounding == Rounding::Down
+            };
+            return zero(sign);
+        },
+        (Class::Zero, _) => return round_value(b, env, exc),
+        (_, Class::Zero) => return round_value(a, env, exc),
+        _ => {},
+    }
+
+    // Line the smaller operand up with the larger one
+    let (sa, ea, ma) = unpack(&a);
+    let (sb, eb, mb) = unpack(&b);
+    let (sa, ea, ma, sb, eb, mb) = if ea >= eb {
+        (sa, ea, ma, sb, eb, mb)
+    } else {
+        (sb, eb, mb, sa, ea, ma)
+    };
+    let (b_hi, b_lo) = shr128_sticky(mb, 0, (ea - eb) as uint);
+
+    if sa == sb {
+        let (hi, lo, carry) = add128(ma, 0, b_hi, b_lo);
+        if carry {
+            let (hi, lo) = shr128_sticky(hi, lo, 1);
+            return round_extended(sa, ea + 1, hi | INTEGER_BIT, lo, env, exc);
+        }
+        return round_extended(sa, ea, hi, lo, env, exc);
+    }
+
+    let (hi, lo, sign) = match cmp128(ma, 0, b_hi, b_lo) {
+        Ordering::Less => {
+            let (hi, lo) = sub128(b_hi, b_lo, ma, 0);
+            (hi, lo, sb)
+        },
+        _ => {
+            let (hi, lo) = sub128(ma, 0, b_hi, b_lo);
+            (hi, lo, sa)
+        },
+    };
+    if hi == 0 && lo == 0 {
+        return zero(env.rounding == Rounding::Down);
+    }
+    round_extended(sign, ea, hi, lo, env, exc)
+}
+
+pub fn mul(a: F80, b: F80, env: &Env, exc: &mut Word) -> F80 {
+    match propagate_nan(&a, &b, exc) {
+        Some(nan) => return nan,
+        None => {},
+    }
+    check_denormal(&a, exc);
+    check_denormal(&b, exc);
+
+    let sign = a.sign != b.sign;
+    match (a.class(), b.class()) {
+        (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity) => {
+            *exc = *exc | INVALID;
+            return indefinite();
+        },
+        (Class::Infinity, _) | (_, Class::Infinity) => return infinity(sign),
+        (Class::Zero, _) | (_, Class::Zero) => return zero(sign),
+        _ => {},
+    }
+
+    let (_, ea, ma) = unpack(&a);
+    let (_, eb, mb) = unpack(&b);
+    let (hi, lo) = mul64(ma, mb);
+    round_extended(sign, ea + eb + 1, hi, lo, env, exc)
+}
+
+pub fn div(a: F80, b: F80, env: &Env, exc: &mut Word) -> F80 {
+    match propagate_nan(&a, &b, exc) {
+        Some(nan) => return nan,
+        None => {},
+    }
+    check_denormal(&a, exc);
+    check_denormal(&b, exc);
+
+    let sign = a.sign != b.sign;
+    match (a.class(), b.class()) {
+        (Class::Infinity, Class::Infinity) | (Class::Zero, Class::Zero) => {
+            *exc = *exc | INVALID;
+            return indefinite();
+        },
+        (Class::Infinity, _) => return infinity(sign),
+        (_, Class::Infinity) | (Class::Zero, _) => return zero(sign),
+        (_, Class::Zero) => {
+            *exc = *exc | ZERO_DIVIDE;
+            return infinity(sign);
+        },
+        _ => {},
+    }
+
+    let (_, ea, ma) = unpack(&a);
+    let (_, eb, mb) = unpack(&b);
+
+    // Restoring division, one quotient bit per step. `carry' is bit 64 of
+    // the partial remainder.
+    let mut rem = ma;
+    let mut carry = false;
+    let mut exp = ea - eb;
+    if ma < mb {
+        carry = rem >> 63 == 1;
+        rem = rem << 1;
+        exp = exp - 1;
+    }
+
+    let mut q_hi: u64 = 0;
+    let mut q_lo: u64 = 0;
+    for _ in range(0u, 128) {
+        let bit = carry || rem >= mb;
+        if bit {
+            rem = rem - mb;
+        }
+        q_hi = (q_hi << 1) | (q_lo >> 63);
+        q_lo = (q_lo << 1) | sticky(bit);
+        carry = rem >> 63 == 1;
+        rem = rem << 1;
+    }
+    q_lo = q_lo | sticky(rem != 0 || carry);
+
+    round_extended(sign, exp, q_hi, q_lo, env, exc)
+}
+
+/// Integer square root of a 128-bit value. Returns the root and remainder.
+fn isqrt128(hi: u64, lo: u64) -> (u64, u64, u64) {
+    let mut root: u64 = 0;
+    let mut rem_hi: u64 = 0;
+    let mut rem_lo: u64 = 0;
+
+    for i in range(0u, 64).rev() {
+        // Bring down the next two bits of the radicand
+        let bits = if i >= 32 {
+            (hi >> ((i - 32) * 2)) & 3
+        } else {
+            (lo >> (i * 2)) & 3
+        };
+        let (h, l) = shl128(rem_hi, rem_lo, 2);
+        rem_hi = h;
+        rem_lo = l | bits;
+
+        let (trial_hi, trial_lo) = shl128(0, root, 2);
+        let trial_lo = trial_lo | 1;
+        if cmp128(rem_hi, rem_lo, trial_hi, trial_lo) != Ordering::Less {
+            let (h, l) = sub128(rem_hi, rem_lo, trial_hi, trial_lo);
+            rem_hi = h;
+            rem_lo = l;
+            root = (root << 1) | 1;
+        } else {
+            root = root << 1;
+        }
+    }
+
+    (root, rem_hi, rem_lo)
+}
+
+pub fn sqrt(x: F80, env: &Env, exc: &mut Word) -> F80 {
+    match propagate_nan(&x, &x, exc) {
+        Some(nan) => return nan,
+        None => {},
+    }
+    check_denormal(&x, exc);
+
+    match x.class() {
+        Class::Zero => return x,
+        _ if x.sign => {
+            *exc = *exc | INVALID;
+            return indefinite();
+        },
+        Class::Infinity => return x,
+        _ => {},
+    }
+
+    // Make the exponent even so it can be halved exactly
+    let (_, e, m) = unpack(&x);
+    let (r_hi, r_lo, exp) = if e & 1 == 1 {
+        (m, 0, (e - 127) / 2 + 63)
+    } else {
+        (m >> 1, m << 63, (e - 126) / 2 + 63)
+    };
+
+    let (root, rem_hi, rem_lo) = isqrt128(r_hi, r_lo);
+
+    // The remainder tells whether the rest of the root is above or below
+    // one half; it can never be exactly one half.
+    let rest = if cmp128(rem_hi, rem_lo, 0, root) == Ordering::Greater {
+        INTEGER_BIT | QUIET_BIT
+    } else {
+        sticky(rem_hi != 0 || rem_lo != 0)
+    };
+
+    round_extended(false, exp, root, rest, env, exc)
+}
+
+/// Comparison key for magnitudes: (exponent, significand)
+fn magnitude(x: &F80) -> (i32, u64) {
+    match x.class() {
+        Class::Zero => (i32::MIN, 0),
+        Class::Infinity => (i32::MAX, 0),
+        _ => {
+            let (_, exp, mantissa) = unpack(x);
+            (exp, mantissa)
+        },
+    }
+}
+
+/// Compare two values as FCOM does. Any NaN makes the result unordered
+/// and raises an invalid operation. The signs of zeros are ignored.
+pub fn compare(a: &F80, b: &F80, exc: &mut Word) -> Comparison {
+    if a.class() == Class::NaN || b.class() == Class::NaN {
+        *exc = *exc | INVALID;
+        return Comparison::Unordered;
+    }
+    check_denormal(a, exc);
+    check_denormal(b, exc);
+
+    let a_zero = a.class() == Class::Zero;
+    let b_zero = b.class() == Class::Zero;
+    if a_zero && b_zero {
+        return Comparison::Equal;
+    }
+
+    let a_neg = a.sign && !a_zero;
+    let b_neg = b.sign && !b_zero;
+    if a_neg != b_neg {
+        return if a_neg { Comparison::Less } else { Comparison::Greater };
+    }
+
+    let ordering = magnitude(a).cmp(&magnitude(b));
+    let ordering = if a_neg { ordering.reverse() } else { ordering };
+    match ordering {
+        Ordering::Less => Comparison::Less,
+        Ordering::Equal => Comparison::Equal,
+        Ordering::Greater => Comparison::Greater,
+    }
+}
+
+
+/// Build an exact value from a sign and integer magnitude
+pub fn from_magnitude(sign: bool, magnitude: u64) -> F80 {
+    if magnitude == 0 {
+        return zero(sign);
+    }
+    let shift = magnitude.leading_zeros();
+    F80 {
+        sign: sign,
+        exponent: (BIAS + 63 - shift as i32) as u16,
+        mantissa: magnitude << shift,
+    }
+}
+
+pub fn from_i64(val: i64) -> F80 {
+    if val < 0 {
+        // Negate via val + 1 so that i64::MIN doesn't overflow
+        from_magnitude(true, (-(val + 1)) as u64 + 1)
+    } else {
+        from_magnitude(false, val as u64)
+    }
+}
+
+/// Round to an integer with `rounding' and return its sign and magnitude,
+/// or None if it is a NaN, an infinity, or doesn't fit in 64 bits.
+pub fn to_integer(x: &F80, rounding: Rounding, exc: &mut Word) -> Option<(bool, u64)> {
+    match x.class() {
+        Class::NaN | Class::Infinity => return None,
+        Class::Zero => return Some((x.sign, 0)),
+        _ => {},
+    }
+    check_denormal(x, exc);
+
+    let (sign, exp, mantissa) = unpack(x);
+    if exp > 63 {
+        return None;
+    }
+
+    // Split into the integer part and the fraction, left-aligned
+    let (int, rest) = if exp == 63 {
+        (mantissa, 0)
+    } else if exp >= 0 {
+        let frac = (63 - exp) as uint;
+        (mantissa >> frac, mantissa << (64 - frac))
+    } else {
+        let (_, rest) = shr128_sticky(0, mantissa, (-exp - 1) as uint);
+        (0, rest)
+    };
+
+    let inexact = rest != 0;
+    let round_up = match rounding {
+        Rounding::Nearest => rest > INTEGER_BIT || (rest == INTEGER_BIT && int & 1 == 1),
+        Rounding::Down => inexact && sign,
+        Rounding::Up => inexact && !sign,
+        Rounding::Chop => false,
+    };
+    if inexact {
+        *exc = *exc | PRECISION;
+    }
+
+    if round_up {
+        match int.checked_add(1) {
+            Some(int) => Some((sign, int)),
+            None => None,
+        }
+    } else {
+        Some((sign, int))
+    }
+}
+
+/// FRNDINT: round to an integral value with `rounding'
+pub fn round_to_integer(x: F80, rounding: Rounding, exc: &mut Word) -> F80 {
+    match x.class() {
+        Class::NaN => {
+            if is_signaling(&x) {
+                *exc = *exc | INVALID;
+            }
+            return quiet(x);
+        },
+        Class::Zero | Class::Infinity => return x,
+        _ => {},
+    }
+
+    let (_, exp, _) = unpack(&x);
+    if exp >= 63 {
+        return x;
+    }
+
+    match to_integer(&x, rounding, exc) {
+        Some((sign, magnitude)) => from_magnitude(sign, magnitude),
+        None => indefinite(),
+    }
+}
+
+/// FSCALE: x * 2^n
+pub fn scale(x: F80, n: i32, env: &Env, exc: &mut Word) -> F80 {
+    match x.class() {
+        Class::NaN => {
+            if is_signaling(&x) {
+                *exc = *exc | INVALID;
+            }
+            quiet(x)
+        },
+        Class::Zero | Class::Infinity => x,
+        _ => {
+            check_denormal(&x, exc);
+            let (sign, exp, mantissa) = unpack(&x);
+            round_extended(sign, exp + n, mantissa, 0, env, exc)
+        },
+    }
+}
+
+/// FXTRACT: split a finite non-zero value into its unbiased exponent and
+/// its significand, scaled to [1, 2)
+pub fn extract(x: &F80) -> (F80, F80) {
+    let (sign, exp, mantissa) = unpack(x);
+    let significand = F80 { sign: sign, exponent: BIAS as u16, mantissa: mantissa };
+    (from_i64(exp as i64), significand)
+}
+
+/// FPREM: the partial remainder of a / b, truncating the quotient. The
+/// 8087 reduces the exponent by at most 63 per instruction. Returns the
+/// remainder, the low three bits of the quotient, and whether the
+/// reduction is complete.
+pub fn partial_remainder(a: F80, b: F80, exc: &mut Word) -> (F80, u64, bool) {
+    match propagate_nan(&a, &b, exc) {
+        Some(nan) => return (nan, 0, true),
+        None => {},
+    }
+    check_denormal(&a, exc);
+    check_denormal(&b, exc);
+
+    match (a.class(), b.class()) {
+        (Class::Infinity, _) | (_, Class::Zero) => {
+            *exc = *exc | INVALID;
+            return (indefinite(), 0, true);
+        },
+        (Class::Zero, _) | (_, Class::Infinity) => return (a, 0, true),
+        _ => {},
+    }
+
+    let (sign, ea, ma) = unpack(&a);
+    let (_, eb, mb) = unpack(&b);
+    if ea < eb {
+        return (a, 0, true);
+    }
+
+    let diff = (ea - eb) as uint;
+    let steps = if diff > 63 { 63 } else { diff };
+
+    // Long division of ma * 2^steps by mb
+    let mut rem = ma;
+    let mut quotient: u64 = 0;
+    if rem >= mb {
+        rem = rem - mb;
+        quotient = 1;
+    }
+    for _ in range(0, steps) {
+        let carry = rem >> 63 == 1;
+        rem = rem << 1;
+        quotient = quotient << 1;
+        if carry || rem >= mb {
+            rem = rem - mb;
+            quotient = quotient | 1;
+        }
+    }
+
+    let remainder = if rem == 0 {
+        zero(sign)
+    } else {
+        let env = Env { rounding: Rounding::Chop, precision: 64 };
+        round_extended(sign, ea - steps as i32, rem, 0, &env, exc)
+    };
+    (remainder, quotient & 7, diff <= 63)
+}
+
+
+// Transcendental functions. These are evaluated with series in extended
+// precision and are only defined over the 8087's operand ranges.
+
+fn small_int(n: uint) -> F80 {
+    from_magnitude(false, n as u64)
+}
+
+/// 2^x - 1, for 0 <= x <= 0.5
+pub fn f2xm1(x: F80, exc: &mut Word) -> F80 {
+    let mut scratch: Word = 0;
+    let t = mul(x, LN2, &INTERNAL, &mut scratch);
+
+    let mut term = t;
+    let mut sum = t;
+    for k in range(2u, 30) {
+        term = div(mul(term, t, &INTERNAL, &mut scratch), small_int(k), &INTERNAL, &mut scratch);
+        sum = add(sum, term, &INTERNAL, &mut scratch);
+    }
+
+    *exc = *exc | PRECISION;
+    sum
+}
+
+/// 2 * atanh(t), which is ln((1 + t) / (1 - t)), for small t
+fn atanh2(t: F80) -> F80 {
+    let mut scratch: Word = 0;
+    let t2 = mul(t, t, &INTERNAL, &mut scratch);
+
+    let mut power = t;
+    let mut sum = t;
+    for k in range(1u, 40) {
+        power = mul(power, t2, &INTERNAL, &mut scratch);
+        let term = div(power, small_int(2 * k + 1), &INTERNAL, &mut scratch);
+        sum = add(sum, term, &INTERNAL, &mut scratch);
+    }
+    add(sum, sum, &INTERNAL, &mut scratch)
+}
+
+/// log2(x), for finite x > 0
+pub fn log2(x: F80, exc: &mut Word) -> F80 {
+    let mut scratch: Word = 0;
+    let (_, exp, mantissa) = unpack(&x);
+    let m = F80 { sign: false, exponent: BIAS as u16, mantissa: mantissa };
+
+    // ln(m) = 2 * atanh((m - 1) / (m + 1)), with m in [1, 2)
+    let t = div(sub(m, ONE, &INTERNAL, &mut scratch),
+                add(m, ONE, &INTERNAL, &mut scratch),
+                &INTERNAL,
+                &mut scratch);
+    let log2_m = mul(atanh2(t), L2E, &INTERNAL, &mut scratch);
+
+    *exc = *exc | PRECISION;
+    add(from_i64(exp as i64), log2_m, &INTERNAL, &mut scratch)
+}
+
+/// log2(x + 1), for |x| < 1 - sqrt(2) / 2
+pub fn log2_1p(x: F80, exc: &mut Word) -> F80 {
+    let mut scratch: Word = 0;
+    let two = small_int(2);
+
+    // ln(1 + x) = 2 * atanh(x / (2 + x))
+    let t = div(x, add(two, x, &INTERNAL, &mut scratch), &INTERNAL, &mut scratch);
+
+    *exc = *exc | PRECISION;
+    mul(atanh2(t), L2E, &INTERNAL, &mut scratch)
+}
+
+/// FPTAN: return (y, x) with y / x = tan(angle), for 0 <= angle < pi/4
+pub fn tan_parts(angle: F80, exc: &mut Word) -> (F80, F80) {
+    let mut scratch: Word = 0;
+    let a2 = mul(angle, angle, &INTERNAL, &mut scratch).negate();
+
+    let mut sin_term = angle;
+    let mut sin_sum = angle;
+    let mut cos_term = ONE;
+    let mut cos_sum = ONE;
+    for k in range(1u, 20) {
+        let sin_div = small_int((2 * k) * (2 * k + 1));
+        sin_term = div(mul(sin_term, a2, &INTERNAL, &mut scratch), sin_div, &INTERNAL, &mut scratch);
+        sin_sum = add(sin_sum, sin_term, &INTERNAL, &mut scratch);
+
+        let cos_div = small_int((2 * k - 1) * (2 * k));
+        cos_term = div(mul(cos_term, a2, &INTERNAL, &mut scratch), cos_div, &INTERNAL, &mut scratch);
+        cos_sum = add(cos_sum, cos_term, &INTERNAL, &mut scratch);
+    }
+
+    *exc = *exc | PRECISION;
+    (sin_sum, cos_sum)
+}
+
+/// atan(z) by its Taylor series, for small z
+fn atan_series(z: F80) -> F80 {
+    let mut scratch: Word = 0;
+    let z2 = mul(z, z, &INTERNAL, &mut scratch).negate();
+
+    let mut power = z;
+    let mut sum = z;
+    for k in range(1u, 40) {
+        power = mul(power, z2, &INTERNAL, &mut scratch);
+        let term = div(power, small_int(2 * k + 1), &INTERNAL, &mut scratch);
+        sum = add(sum, term, &INTERNAL, &mut scratch);
+    }
+    sum
+}
+
+/// FPATAN: the angle of the point (x, y). The 8087 only defines this for
+/// 0 <= y < x; other quadrants are handled as on later coprocessors.
+pub fn atan2(y: F80, x: F80, exc: &mut Word) -> F80 {
+    let mut scratch: Word = 0;
+    let ay = y.abs();
+    let ax = x.abs();
+
+    let swap = compare(&ay, &ax, &mut scratch) == Comparison::Greater;
+    let mut z = if swap {
+        div(ax, ay, &INTERNAL, &mut scratch)
+    } else {
+        div(ay, ax, &INTERNAL, &mut scratch)
+    };
+
+    // atan(z) = 2 * atan(z / (1 + sqrt(1 + z^2))), applied twice to
+    // speed up the series
+    for _ in range(0u, 2) {
+        let z2 = mul(z, z, &INTERNAL, &mut scratch);
+        let root = sqrt(add(ONE, z2, &INTERNAL, &mut scratch), &INTERNAL, &mut scratch);
+        z = div(z, add(ONE, root, &INTERNAL, &mut scratch), &INTERNAL, &mut scratch);
+    }
+    let mut angle = mul(atan_series(z), small_int(4), &INTERNAL, &mut scratch);
+
+    if swap {
+        let half_pi = scale(PI, -1, &INTERNAL, &mut scratch);
+        angle = sub(half_pi, angle, &INTERNAL, &mut scratch);
+    }
+    if x.sign {
+        angle = sub(PI, angle, &INTERNAL, &mut scratch);
+    }
+    if y.sign {
+        angle = angle.negate();
+    }
+
+    *exc = *exc | PRECISION;
+    angle
+}
+
+
+/// Load an IEEE single or double from its bit pattern
+pub fn from_bits(bits: u64, format: &Format, exc: &mut Word) -> F80 {
+    let frac_bits = format.precision - 1;
+    let frac = bits & ((1 << frac_bits) - 1);
+    let exp = ((bits >> frac_bits) & (format.max_exp as u64)) as i32;
+    let sign = (bits >> (frac_bits + format.exponent_bits)) & 1 == 1;
+
+    if exp == format.max_exp {
+        if frac == 0 {
+            return infinity(sign);
+        }
+        return F80 {
+            sign: sign,
+            exponent: 0x7FFF,
+            mantissa: INTEGER_BIT | (frac << (64 - format.precision)),
+        };
+    }
+
+    if exp == 0 {
+        if frac == 0 {
+            return zero(sign);
+        }
+        *exc = *exc | DENORMAL;
+        let shift = frac.leading_zeros();
+        let exponent = BIAS + (1 - format.bias - frac_bits as i32) + (63 - shift as i32);
+        return F80 { sign: sign, exponent: exponent as u16, mantissa: frac << shift };
+    }
+
+    F80 {
+        sign: sign,
+        exponent: (exp - format.bias + BIAS) as u16,
+        mantissa: INTEGER_BIT | (frac << (64 - format.precision)),
+    }
+}
+
+/// Round to an IEEE single or double and return its bit pattern
+pub fn to_bits(x: &F80, format: &Format, rounding: Rounding, exc: &mut Word) -> u64 {
+    let frac_bits = format.precision - 1;
+    let sign_bit: u64 = if x.sign { 1 << (frac_bits + format.exponent_bits) } else { 0 };
+    let max_exp = (format.max_exp as u64) << frac_bits;
+
+    match x.class() {
+        Class::Zero => return sign_bit,
+        Class::Infinity => return sign_bit | max_exp,
+        Class::NaN => {
+            if is_signaling(x) {
+                *exc = *exc | INVALID;
+            }
+            let frac = ((x.mantissa | QUIET_BIT) & !INTEGER_BIT) >> (64 - format.precision);
+            return sign_bit | max_exp | frac;
+        },
+        _ => {},
+    }
+    check_denormal(x, exc);
+
+    let (sign, exp, mantissa) = unpack(x);
+    let (_, biased, kept) = round(sign, exp, mantissa, 0, format, rounding, exc);
+    let frac = (kept & !INTEGER_BIT) >> (64 - format.precision);
+    sign_bit | ((biased as u64) << frac_bits) | frac
+}
+
+/// Round to a `bits'-wide two's complement integer, as FIST does. Values
+/// out of range raise an invalid operation and give the integer
+/// indefinite, the most negative integer.
+pub fn to_int_bits(x: &F80, bits: uint, rounding: Rounding, exc: &mut Word) -> u64 {
+    let limit: u64 = 1 << (bits - 1);
+    let int = match to_integer(x, rounding, exc) {
+        Some((true, magnitude)) if magnitude <= limit => 0 - magnitude,
+        Some((false, magnitude)) if magnitude < limit => magnitude,
+        _ => {
+            *exc = *exc | INVALID;
+            limit
+        },
+    };
+    int & (!0u64 >> (64 - bits))
+}
+
+/// Round to the packed BCD format of FBSTP: 18 digits, two per byte with
+/// the least significant byte first, then a sign byte. Values out of range
+/// raise an invalid operation and give the BCD indefinite.
+pub fn to_bcd(x: &F80, rounding: Rounding, exc: &mut Word) -> [Byte; 10] {
+    let mut bcd = [0u8; 10];
+    match to_integer(x, rounding, exc) {
+        Some((sign, magnitude)) if magnitude < 1000000000000000000 => {
+            let mut rest = magnitude;
+            for i in range(0u, 9) {
+                let pair = rest % 100;
+                rest = rest / 100;
+                bcd[i] = (((pair / 10) << 4) | (pair % 10)) as Byte;
+            }
+            bcd[9] = if sign { 0x80 } else { 0 };
+        },
+        _ => {
+            *exc = *exc | INVALID;
+            bcd[7] = 0xC0;
+            bcd[8] = 0xFF;
+            bcd[9] = 0xFF;
+        },
+    }
+    bcd
+}
+
+/// Load the packed BCD format of FBLD
+pub fn from_bcd(bcd: &[Byte; 10]) -> F80 {
+    let mut magnitude: u64 = 0;
+    for i in range(0u, 9).rev() {
+        let pair = bcd[i] as u64;
+        magnitude = magnitude * 100 + (pair >> 4) * 10 + (pair & 0xF);
+    }
+    from_magnitude(bcd[9] & 0x80 != 0, magnitude)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+    use datatypes::Word;
+    use super::{F80, Rounding, Comparison, Env, SINGLE, DOUBLE, ONE, BIAS, INTEGER_BIT};
+    use super::{INVALID, DENORMAL, ZERO_DIVIDE, OVERFLOW, UNDERFLOW, PRECISION};
+    use super::{zero, infinity, indefinite, add, sub, mul, div, sqrt, compare};
+    use super::{partial_remainder, from_i64, from_bits, to_bits};
+    use super::{to_int_bits, to_bcd, from_bcd};
+
+    const EXTENDED: Env = Env { rounding: Rounding::Nearest, precision: 64 };
+
+    fn fields(x: F80) -> (bool, u16, u64) {
+        (x.sign, x.exponent, x.mantissa)
+    }
+
+    fn int(val: i64) -> F80 {
+        from_i64(val)
+    }
+
+    /// The value of an IEEE double bit pattern
+    fn double(bits: u64) -> F80 {
+        let mut exc: Word = 0;
+        from_bits(bits, &DOUBLE, &mut exc)
+    }
+
+    #[test]
+    fn real_round_trip() {
+        let singles = [0x3F800000u64, 0xC0200000, 0x7F7FFFFF, 0x00800000, 0x80000000, 0x7F800000];
+        for &bits in singles.iter() {
+            let mut exc: Word = 0;
+            let x = from_bits(bits, &SINGLE, &mut exc);
+            assert_eq!(to_bits(&x, &SINGLE, Rounding::Nearest, &mut exc), bits);
+            assert_eq!(exc, 0);
+        }
+
+        let doubles = [0x3FF0000000000000u64, 0xC004000000000000, 0x7FEFFFFFFFFFFFFF,
+                       0x0010000000000000, 0xFFF0000000000000];
+        for &bits in doubles.iter() {
+            let mut exc: Word = 0;
+            let x = from_bits(bits, &DOUBLE, &mut exc);
+            assert_eq!(to_bits(&x, &DOUBLE, Rounding::Nearest, &mut exc), bits);
+            assert_eq!(exc, 0);
+        }
+
+        assert_eq!(fields(double(0x3FF0000000000000)), fields(ONE));
+    }
+
+    #[test]
+    fn denormal_round_trip() {
+        let mut exc: Word = 0;
+        let x = from_bits(0x00000001, &SINGLE, &mut exc);
+        assert_eq!(fields(x), (false, (BIAS - 149) as u16, INTEGER_BIT));
+        assert_eq!(exc, DENORMAL);
+
+        exc = 0;
+        assert_eq!(to_bits(&x, &SINGLE, Rounding::Nearest, &mut exc), 0x00000001);
+        assert_eq!(exc, 0);
+
+        let x = from_bits(0x800FFFFFFFFFFFFF, &DOUBLE, &mut exc);
+        assert_eq!(exc, DENORMAL);
+
+        exc = 0;
+        assert_eq!(to_bits(&x, &DOUBLE, Rounding::Nearest, &mut exc), 0x800FFFFFFFFFFFFF);
+        assert_eq!(exc, 0);
+    }
+
+    #[test]
+    fn real_overflow_and_underflow() {
+        let max = double(0x7FEFFFFFFFFFFFFF);
+        let mut exc: Word = 0;
+        assert_eq!(to_bits(&max, &SINGLE, Rounding::Nearest, &mut exc), 0x7F800000);
+        assert_eq!(exc, OVERFLOW | PRECISION);
+
+        exc = 0;
+        assert_eq!(to_bits(&max, &SINGLE, Rounding::Chop, &mut exc), 0x7F7FFFFF);
+        assert_eq!(exc, OVERFLOW | PRECISION);
+
+        // 2^-150 is half the smallest single denormal
+        let tiny = double(0x3690000000000000);
+        exc = 0;
+        assert_eq!(to_bits(&tiny, &SINGLE, Rounding::Nearest, &mut exc), 0);
+        assert_eq!(exc, UNDERFLOW | PRECISION);
+
+        exc = 0;
+        assert_eq!(to_bits(&tiny, &SINGLE, Rounding::Up, &mut exc), 0x00000001);
+        assert_eq!(exc, UNDERFLOW | PRECISION);
+    }
+
+    #[test]
+    fn nan_encodings() {
+        let mut exc: Word = 0;
+        assert_eq!(to_bits(&indefinite(), &SINGLE, Rounding::Nearest, &mut exc), 0xFFC00000);
+        assert_eq!(to_bits(&indefinite(), &DOUBLE, Rounding::Nearest, &mut exc), 0xFFF8000000000000);
+        assert_eq!(exc, 0);
+
+        // Storing a signaling NaN quiets it
+        let snan = from_bits(0x7F800001, &SINGLE, &mut exc);
+        assert_eq!(to_bits(&snan, &SINGLE, Rounding::Nearest, &mut exc), 0x7FC00001);
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn rounding_modes() {
+        // 1 + 2^-24 is halfway between two singles and 1 + 3 * 2^-25 is
+        // nearer the upper one
+        let half = F80 { sign: false, exponent: 0x3FFF, mantissa: INTEGER_BIT | (1 << 39) };
+        let above = F80 { sign: false, exponent: 0x3FFF, mantissa: INTEGER_BIT | (3 << 38) };
+        let cases = [
+            (Rounding::Nearest, 0x3F800000u64, 0x3F800001u64, 0xBF800000u64),
+            (Rounding::Down, 0x3F800000, 0x3F800000, 0xBF800001),
+            (Rounding::Up, 0x3F800001, 0x3F800001, 0xBF800000),
+            (Rounding::Chop, 0x3F800000, 0x3F800000, 0xBF800000),
+        ];
+        for &(rounding, half_bits, above_bits, negative_bits) in cases.iter() {
+            let mut exc: Word = 0;
+            assert_eq!(to_bits(&half, &SINGLE, rounding, &mut exc), half_bits);
+            assert_eq!(to_bits(&above, &SINGLE, rounding, &mut exc), above_bits);
+            assert_eq!(to_bits(&half.negate(), &SINGLE, rounding, &mut exc), negative_bits);
+            assert_eq!(exc, PRECISION);
+        }
+
+        // A tie next to an odd significand rounds up to even
+        let odd = F80 { sign: false, exponent: 0x3FFF, mantissa: INTEGER_BIT | (3 << 39) };
+        let mut exc: Word = 0;
+        assert_eq!(to_bits(&odd, &SINGLE, Rounding::Nearest, &mut exc), 0x3F800002);
+
+        // 2.5 and -2.5 to integers
+        let cases = [
+            (Rounding::Nearest, 2i64, -2i64),
+            (Rounding::Down, 2, -3),
+            (Rounding::Up, 3, -2),
+            (Rounding::Chop, 2, -2),
+        ];
+        for &(rounding, positive, negative) in cases.iter() {
+            let mut exc: Word = 0;
+            let x = double(0x4004000000000000);
+            assert_eq!(to_int_bits(&x, 64, rounding, &mut exc), positive as u64);
+            assert_eq!(to_int_bits(&x.negate(), 64, rounding, &mut exc), negative as u64);
+            assert_eq!(exc, PRECISION);
+        }
+    }
+
+    #[test]
+    fn addition_and_cancellation() {
+        let mut exc: Word = 0;
+        assert_eq!(fields(add(int(1), int(2), &EXTENDED, &mut exc)), fields(int(3)));
+        let x = double(0x3FF8000000000000);
+        assert_eq!(fields(add(x, x, &EXTENDED, &mut exc)), fields(int(3)));
+        assert_eq!(exc, 0);
+
+        // Exact cancellation gives +0, or -0 when rounding down
+        let down = Env { rounding: Rounding::Down, precision: 64 };
+        assert_eq!(fields(sub(int(5), int(5), &EXTENDED, &mut exc)), (false, 0, 0));
+        assert_eq!(fields(add(int(-5), int(5), &EXTENDED, &mut exc)), (false, 0, 0));
+        assert_eq!(fields(sub(int(5), int(5), &down, &mut exc)), (true, 0, 0));
+
+        // Cancelling all but the last bit renormalizes exactly
+        let next = F80 { sign: false, exponent: 0x3FFF, mantissa: INTEGER_BIT | 1 };
+        assert_eq!(fields(sub(next, ONE, &EXTENDED, &mut exc)), (false, 0x3FFF - 63, INTEGER_BIT));
+        assert_eq!(exc, 0);
+
+        // 1 + 2^-64 is a tie and rounds back to 1
+        let tiny = F80 { sign: false, exponent: 0x3FFF - 64, mantissa: INTEGER_BIT };
+        assert_eq!(fields(add(ONE, tiny, &EXTENDED, &mut exc)), fields(ONE));
+        assert_eq!(exc, PRECISION);
+
+        exc = 0;
+        assert_eq!(fields(add(infinity(false), infinity(true), &EXTENDED, &mut exc)),
+                   fields(indefinite()));
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn multiplication() {
+        let mut exc: Word = 0;
+        assert_eq!(fields(mul(int(3), int(5), &EXTENDED, &mut exc)), fields(int(15)));
+        assert_eq!(fields(mul(int(-4), int(3), &EXTENDED, &mut exc)), fields(int(-12)));
+        let x = double(0x3FF8000000000000);
+        assert_eq!(fields(mul(x, x, &EXTENDED, &mut exc)), (false, 0x4000, 0x9000000000000000));
+        assert_eq!(exc, 0);
+
+        let max = F80 { sign: false, exponent: 0x7FFE, mantissa: !0 };
+        assert_eq!(fields(mul(max, int(2), &EXTENDED, &mut exc)), fields(infinity(false)));
+        assert_eq!(exc, OVERFLOW | PRECISION);
+
+        exc = 0;
+        assert_eq!(fields(mul(infinity(false), zero(true), &EXTENDED, &mut exc)),
+                   fields(indefinite()));
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn division() {
+        let mut exc: Word = 0;
+        assert_eq!(fields(div(int(6), int(3), &EXTENDED, &mut exc)), fields(int(2)));
+        assert_eq!(fields(div(int(-7), int(2), &EXTENDED, &mut exc)),
+                   fields(double(0xC00C000000000000)));
+        assert_eq!(exc, 0);
+
+        assert_eq!(fields(div(ONE, int(3), &EXTENDED, &mut exc)),
+                   (false, 0x3FFD, 0xAAAAAAAAAAAAAAAB));
+        let chop = Env { rounding: Rounding::Chop, precision: 64 };
+        assert_eq!(fields(div(ONE, int(3), &chop, &mut exc)),
+                   (false, 0x3FFD, 0xAAAAAAAAAAAAAAAA));
+        let single = Env { rounding: Rounding::Nearest, precision: 24 };
+        assert_eq!(fields(div(ONE, int(3), &single, &mut exc)),
+                   (false, 0x3FFD, 0xAAAAAB0000000000));
+        assert_eq!(exc, PRECISION);
+
+        exc = 0;
+        assert_eq!(fields(div(ONE, zero(true), &EXTENDED, &mut exc)), fields(infinity(true)));
+        assert_eq!(exc, ZERO_DIVIDE);
+
+        exc = 0;
+        assert_eq!(fields(div(zero(false), zero(false), &EXTENDED, &mut exc)),
+                   fields(indefinite()));
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn square_root() {
+        let mut exc: Word = 0;
+        assert_eq!(fields(sqrt(int(4), &EXTENDED, &mut exc)), fields(int(2)));
+        assert_eq!(fields(sqrt(int(9), &EXTENDED, &mut exc)), fields(int(3)));
+        assert_eq!(fields(sqrt(zero(true), &EXTENDED, &mut exc)), (true, 0, 0));
+        assert_eq!(exc, 0);
+
+        assert_eq!(fields(sqrt(int(2), &EXTENDED, &mut exc)),
+                   (false, 0x3FFF, 0xB504F333F9DE6484));
+        let up = Env { rounding: Rounding::Up, precision: 64 };
+        assert_eq!(fields(sqrt(int(2), &up, &mut exc)),
+                   (false, 0x3FFF, 0xB504F333F9DE6485));
+        assert_eq!(exc, PRECISION);
+
+        exc = 0;
+        assert_eq!(fields(sqrt(int(-1), &EXTENDED, &mut exc)), fields(indefinite()));
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn comparison() {
+        let mut exc: Word = 0;
+        assert!(compare(&int(1), &int(2), &mut exc) == Comparison::Less);
+        assert!(compare(&int(-1), &int(-2), &mut exc) == Comparison::Greater);
+        assert!(compare(&int(-1), &int(1), &mut exc) == Comparison::Less);
+        assert!(compare(&int(3), &double(0x4008000000000000), &mut exc) == Comparison::Equal);
+        assert!(compare(&zero(false), &zero(true), &mut exc) == Comparison::Equal);
+        assert!(compare(&infinity(true), &int(i64::MIN), &mut exc) == Comparison::Less);
+        assert_eq!(exc, 0);
+
+        let denormal = F80 { sign: false, exponent: 0, mantissa: 1 };
+        assert!(compare(&denormal, &zero(true), &mut exc) == Comparison::Greater);
+        assert_eq!(exc, DENORMAL);
+
+        exc = 0;
+        assert!(compare(&indefinite(), &ONE, &mut exc) == Comparison::Unordered);
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn fprem() {
+        let mut exc: Word = 0;
+        let (rem, quotient, complete) = partial_remainder(int(5), int(3), &mut exc);
+        assert_eq!(fields(rem), fields(int(2)));
+        assert_eq!((quotient, complete), (1, true));
+
+        // The remainder takes the dividend's sign
+        let (rem, quotient, complete) = partial_remainder(int(-7), int(2), &mut exc);
+        assert_eq!(fields(rem), fields(int(-1)));
+        assert_eq!((quotient, complete), (3, true));
+
+        let (rem, quotient, complete) = partial_remainder(int(-6), int(3), &mut exc);
+        assert_eq!(fields(rem), (true, 0, 0));
+        assert_eq!((quotient, complete), (2, true));
+
+        let (rem, quotient, complete) = partial_remainder(int(1), int(3), &mut exc);
+        assert_eq!(fields(rem), fields(ONE));
+        assert_eq!((quotient, complete), (0, true));
+        assert_eq!(exc, 0);
+    }
+
+    #[test]
+    fn fprem_incomplete() {
+        // 2^100 / 3 needs more than 63 exponent steps. The first FPREM
+        // reduces modulo 3 * 2^36, leaving 2^36, and the second finishes.
+        let mut exc: Word = 0;
+        let big = F80 { sign: false, exponent: (BIAS + 100) as u16, mantissa: INTEGER_BIT };
+        let (rem, quotient, complete) = partial_remainder(big, int(3), &mut exc);
+        assert_eq!(fields(rem), (false, (BIAS + 36) as u16, INTEGER_BIT));
+        assert_eq!((quotient, complete), (5, false));
+
+        let (rem, _, complete) = partial_remainder(rem, int(3), &mut exc);
+        assert_eq!(fields(rem), fields(ONE));
+        assert!(complete);
+        assert_eq!(exc, 0);
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(fields(int(-32768)), (true, (BIAS + 15) as u16, INTEGER_BIT));
+        assert_eq!(fields(int(i64::MIN)), (true, (BIAS + 63) as u16, INTEGER_BIT));
+        assert_eq!(fields(int(0)), (false, 0, 0));
+
+        let mut exc: Word = 0;
+        assert_eq!(to_int_bits(&int(32767), 16, Rounding::Nearest, &mut exc), 0x7FFF);
+        assert_eq!(to_int_bits(&int(-32768), 16, Rounding::Nearest, &mut exc), 0x8000);
+        assert_eq!(to_int_bits(&int(-1), 32, Rounding::Nearest, &mut exc), 0xFFFFFFFF);
+        assert_eq!(to_int_bits(&int(i64::MIN), 64, Rounding::Nearest, &mut exc),
+                   0x8000000000000000);
+        assert_eq!(to_int_bits(&zero(true), 16, Rounding::Nearest, &mut exc), 0);
+        assert_eq!(exc, 0);
+
+        // Values out of range, infinities and NaNs store the integer
+        // indefinite
+        assert_eq!(to_int_bits(&int(32768), 16, Rounding::Nearest, &mut exc), 0x8000);
+        assert_eq!(exc, INVALID);
+        exc = 0;
+        assert_eq!(to_int_bits(&int(-2147483649), 32, Rounding::Nearest, &mut exc), 0x80000000);
+        assert_eq!(exc, INVALID);
+        exc = 0;
+        assert_eq!(to_int_bits(&infinity(false), 64, Rounding::Nearest, &mut exc),
+                   0x8000000000000000);
+        assert_eq!(exc, INVALID);
+        exc = 0;
+        assert_eq!(to_int_bits(&indefinite(), 16, Rounding::Nearest, &mut exc), 0x8000);
+        assert_eq!(exc, INVALID);
+    }
+
+    #[test]
+    fn packed_bcd() {
+        let mut exc: Word = 0;
+        let bcd = to_bcd(&int(-1234), Rounding::Nearest, &mut exc);
+        assert_eq!(bcd, [0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(fields(from_bcd(&bcd)), fields(int(-1234)));
+
+        let max = int(999999999999999999);
+        let bcd = to_bcd(&max, Rounding::Nearest, &mut exc);
+        assert_eq!(bcd, [0x99, 0x99, 0x99, 0x99, 0x99, 0x99, 0x99, 0x99, 0x99, 0]);
+        assert_eq!(fields(from_bcd(&bcd)), fields(max));
+        assert_eq!(exc, 0);
+
+        let bcd = to_bcd(&double(0x4004000000000000), Rounding::Up, &mut exc);
+        assert_eq!(bcd, [0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(exc, PRECISION);
+
+        // Values out of range and NaNs store the BCD indefinite
+        let indefinite_bcd = [0u8, 0, 0, 0, 0, 0, 0, 0xC0, 0xFF, 0xFF];
+        exc = 0;
+        assert_eq!(to_bcd(&int(1000000000000000000), Rounding::Nearest, &mut exc), indefinite_bcd);
+        assert_eq!(exc, INVALID);
+        exc = 0;
+        assert_eq!(to_bcd(&indefinite(), Rounding::Nearest, &mut exc), indefinite_bcd);
+        assert_eq!(exc, INVALID);
+    }
+}
@@ -0,0 +1,872 @@
+use cstate::{CpuState, Reg16, SegReg};
+use datatypes::{Byte, Word};
+use f80;
+use f80::{F80, Class, Comparison, Env, Format, Rounding};
+use f80::{INVALID, DENORMAL, ZERO_DIVIDE};
+use modrm;
+use operand::Operand;
+
+
+// Tag word values
+const TAG_VALID: Word = 0b00;
+const TAG_ZERO: Word = 0b01;
+const TAG_SPECIAL: Word = 0b10;
+const TAG_EMPTY: Word = 0b11;
+
+// Status word bits. TOP (bits 11-13) is kept separately.
+const C0: Word = 0x0100;
+const C1: Word = 0x0200;
+const C2: Word = 0x0400;
+const C3: Word = 0x4000;
+const CONDITION: Word = C0 | C1 | C2 | C3;
+const INTERRUPT_REQUEST: Word = 0x0080;
+const TOP_MASK: Word = 0x3800;
+
+// Control word bits
+const EXCEPTION_MASKS: Word = 0x003F;
+const INTERRUPT_MASK: Word = 0x0080;
+const DEFAULT_CONTROL: Word = 0x03FF;
+
+
+/// 8087 coprocessor state. Registers are indexed physically; ST(i) is
+/// register (top + i) & 7.
+pub struct Fpu {
+    regs: [F80; 8],
+    tags: Word, // Two bits per physical register
+    top: uint, // Stack top
+    control: Word, // Control word
+    status: Word, // Exception flags, IR and condition codes
+
+    instr_ptr: uint, // Physical address of the last ESC instruction, prefixes included
+    opcode: Word, // Its low 11 opcode bits
+    operand_ptr: uint, // Physical address of its memory operand
+    error_pending: bool, // Unmasked exception not yet signalled to the CPU
+}
+
+impl Fpu {
+    pub fn new() -> Fpu {
+        Fpu {
+            regs: [f80::zero(false); 8],
+            tags: 0xFFFF,
+            top: 0,
+            control: DEFAULT_CONTROL,
+            status: 0,
+
+            instr_ptr: 0,
+            opcode: 0,
+            operand_ptr: 0,
+            error_pending: false,
+        }
+    }
+
+    /// FINIT
+    pub fn reset(&mut self) {
+        self.tags = 0xFFFF;
+        self.top = 0;
+        self.control = DEFAULT_CONTROL;
+        self.status = 0;
+        self.instr_ptr = 0;
+        self.opcode = 0;
+        self.operand_ptr = 0;
+        self.error_pending = false;
+    }
+
+    pub fn status_word(&self) -> Word {
+        self.status | ((self.top as Word) << 11)
+    }
+
+    pub fn control_word(&self) -> Word {
+        self.control
+    }
+
+    fn record(&mut self, instr_ptr: uint, opcode: Byte, modrm: Byte, operand_ptr: uint) {
+        self.instr_ptr = instr_ptr;
+        self.opcode = (((opcode & 7) as Word) << 8) | modrm as Word;
+        self.operand_ptr = operand_ptr;
+    }
+
+    /// Rounding and precision control from the control word
+    fn env(&self) -> Env {
+        Env {
+            rounding: self.rounding(),
+            precision: match (self.control >> 8) & 3 {
+                0 => 24,
+                2 => 53,
+                _ => 64,
+            },
+        }
+    }
+
+    fn rounding(&self) -> Rounding {
+        match (self.control >> 10) & 3 {
+            0 => Rounding::Nearest,
+            1 => Rounding::Down,
+            2 => Rounding::Up,
+            _ => Rounding::Chop,
+        }
+    }
+
+    fn physical(&self, i: uint) -> uint {
+        (self.top + i) & 7
+    }
+
+    fn tag(&self, i: uint) -> Word {
+        (self.tags >> (self.physical(i) * 2)) & 3
+    }
+
+    fn set_tag(&mut self, i: uint, tag: Word) {
+        let shift = self.physical(i) * 2;
+        self.tags = (self.tags & !(3 << shift)) | (tag << shift);
+    }
+
+    /// Read ST(i). An empty register is a stack underflow, which is an
+    /// invalid operation; the masked response reads the indefinite NaN.
+    fn st(&self, i: uint, exc: &mut Word) -> F80 {
+        if self.tag(i) == TAG_EMPTY {
+            *exc = *exc | INVALID;
+            return f80::indefinite();
+        }
+        self.regs[self.physical(i)]
+    }
+
+    fn set_st(&mut self, i: uint, value: F80) {
+        let tag = match value.class() {
+            Class::Zero => TAG_ZERO,
+            Class::Normal => TAG_VALID,
+            _ => TAG_SPECIAL,
+        };
+        let phys = self.physical(i);
+        self.regs[phys] = value;
+        self.set_tag(i, tag);
+    }
+
+    /// Push onto the stack. Pushing onto a full register is a stack
+    /// overflow; the masked response pushes the indefinite NaN.
+    fn push(&mut self, value: F80, exc: &mut Word) {
+        self.top = (self.top + 7) & 7;
+        if self.tag(0) != TAG_EMPTY {
+            *exc = *exc | INVALID;
+            self.set_st(0, f80::indefinite());
+        } else {
+            self.set_st(0, value);
+        }
+    }
+
+    fn pop(&mut self) {
+        self.set_tag(0, TAG_EMPTY);
+        self.top = (self.top + 1) & 7;
+    }
+
+    fn set_condition(&mut self, condition: Word) {
+        self.status = (self.status & !CONDITION) | condition;
+    }
+
+    /// Unmasked invalid operation, zero divide and denormal exceptions
+    /// leave the destination untouched
+    fn may_store(&self, exc: Word) -> bool {
+        exc & (INVALID | ZERO_DIVIDE | DENORMAL) & !self.control == 0
+    }
+
+    /// Latch the exceptions raised by an instruction. Returns true if one
+    /// is unmasked and the interrupt line should be asserted.
+    fn finish(&mut self, exc: Word) -> bool {
+        self.status = self.status | exc;
+        if exc & !self.control & EXCEPTION_MASKS == 0 {
+            return false;
+        }
+        self.status = self.status | INTERRUPT_REQUEST;
+        self.control & INTERRUPT_MASK == 0
+    }
+
+    /// Take the unmasked exception left by an earlier instruction, if any
+    fn take_error(&mut self) -> bool {
+        let pending = self.error_pending;
+        self.error_pending = false;
+        pending
+    }
+
+    fn compare(&mut self, a: F80, b: F80, exc: &mut Word) {
+        let condition = match f80::compare(&a, &b, exc) {
+            Comparison::Greater => 0,
+            Comparison::Less => C0,
+            Comparison::Equal => C3,
+            Comparison::Unordered => C3 | C2 | C0,
+        };
+        self.set_condition(condition);
+    }
+
+    /// The arithmetic group shared by D8, DA, DC and DE, selected by the
+    /// ModR/M reg field: ADD, MUL, COM, COMP, SUB, SUBR, DIV, DIVR. The
+    /// result replaces ST(dest); `src' is the other operand.
+    fn arithmetic(&mut self, op: u8, dest: uint, src: F80, exc: &mut Word) {
+        let env = self.env();
+        let value = self.st(dest, exc);
+        let result = match op {
+            0 => f80::add(value, src, &env, exc),
+            1 => f80::mul(value, src, &env, exc),
+            2 | 3 => {
+                self.compare(value, src, exc);
+                if op == 3 && self.may_store(*exc) {
+                    self.pop();
+                }
+                return;
+            },
+            4 => f80::sub(value, src, &env, exc),
+            5 => f80::sub(src, value, &env, exc),
+            6 => f80::div(value, src, &env, exc),
+            7 => f80::div(src, value, &env, exc),
+            _ => panic!("Invalid FPU arithmetic op"),
+        };
+        if self.may_store(*exc) {
+            self.set_st(dest, result);
+        }
+    }
+
+    fn load(&mut self, value: F80, exc: &mut Word) {
+        self.push(value, exc);
+    }
+
+    /// FST/FSTP to a single or double real
+    fn store_real(&mut self, format: &Format, pop: bool, exc: &mut Word) -> Option<u64> {
+        let value = self.st(0, exc);
+        let bits = f80::to_bits(&value, format, self.rounding(), exc);
+        self.finish_store(bits, pop, *exc)
+    }
+
+    /// FST/FSTP to a temporary real
+    fn store_extended(&mut self, pop: bool, exc: &mut Word) -> Option<F80> {
+        let value = self.st(0, exc);
+        if !self.may_store(*exc) {
+            return None;
+        }
+        if pop {
+            self.pop();
+        }
+        Some(value)
+    }
+
+    /// FIST/FISTP to a `bits'-wide integer. Values out of range store the
+    /// integer indefinite, the most negative integer.
+    fn store_int(&mut self, bits: uint, pop: bool, exc: &mut Word) -> Option<u64> {
+        let value = self.st(0, exc);
+        let int = f80::to_int_bits(&value, bits, self.rounding(), exc);
+        self.finish_store(int, pop, *exc)
+    }
+
+    /// FBSTP: store as 18 packed BCD digits and a sign byte. Values out of
+    /// range store the BCD indefinite.
+    fn store_bcd(&mut self, exc: &mut Word) -> Option<[Byte; 10]> {
+        let value = self.st(0, exc);
+        let bcd = f80::to_bcd(&value, self.rounding(), exc);
+        self.finish_store(bcd, true, *exc)
+    }
+
+    fn finish_store<T>(&mut self, value: T, pop: bool, exc: Word) -> Option<T> {
+        if !self.may_store(exc) {
+            return None;
+        }
+        if pop {
+            self.pop();
+        }
+        Some(value)
+    }
+
+    /// The 14-byte environment image used by FSTENV and FSAVE
+    fn store_env(&self) -> [Word; 7] {
+        [
+            self.control,
+            self.status_word(),
+            self.tags,
+            self.instr_ptr as Word,
+            (((self.instr_ptr >> 16) & 0xF) << 12) as Word | self.opcode,
+            self.operand_ptr as Word,
+            (((self.operand_ptr >> 16) & 0xF) << 12) as Word,
+        ]
+    }
+
+    fn load_env(&mut self, env: &[Word; 7]) {
+        self.control = env[0];
+        self.status = env[1] & !TOP_MASK;
+        self.top = ((env[1] & TOP_MASK) >> 11) as uint;
+        self.tags = env[2];
+        self.instr_ptr = env[3] as uint | (((env[4] >> 12) as uint) << 16);
+        self.opcode = env[4] & 0x7FF;
+        self.operand_ptr = env[5] as uint | (((env[6] >> 12) as uint) << 16);
+    }
+
+    /// FXAM: classify ST(0) into C3, C2 and C0, with its sign in C1
+    fn examine(&mut self) {
+        let value = self.regs[self.physical(0)];
+        let class = if self.tag(0) == TAG_EMPTY {
+            C3 | C0
+        } else {
+            match value.class() {
+                Class::Unnormal => 0,
+                Class::NaN => C0,
+                Class::Normal => C2,
+                Class::Infinity => C2 | C0,
+                Class::Zero => C3,
+                Class::Denormal => C3 | C2,
+            }
+        };
+        let sign = if value.sign { C1 } else { 0 };
+        self.set_condition(class | sign);
+    }
+
+    /// Register-operand forms (ModR/M mod = 11). `i' is the r/m field.
+    fn register_op(&mut self, opcode: Byte, reg: u8, i: uint) -> bool {
+        let mut exc: Word = 0;
+        let env = self.env();
+
+        match (opcode, reg) {
+            // FADD..FDIVR ST, ST(i)
+            (0xD8, _) | (0xDC, 2) | (0xDC, 3) | (0xDE, 2) => {
+                let src = self.st(i, &mut exc);
+                self.arithmetic(reg, 0, src, &mut exc);
+                if opcode == 0xDE && self.may_store(exc) {
+                    self.pop();
+                }
+            },
+
+            // FCOMPP. The other r/m values are undocumented and compare
+            // with ST(i) before popping twice.
+            (0xDE, 3) => {
+                let a = self.st(0, &mut exc);
+                let b = self.st(i, &mut exc);
+                self.compare(a, b, &mut exc);
+                if self.may_store(exc) {
+                    self.pop();
+                    self.pop();
+                }
+            },
+
+            // FADD..FDIVR ST(i), ST, with FSUB/FSUBR and FDIV/FDIVR
+            // swapped in the encoding. DE pops afterwards.
+            (0xDC, _) | (0xDE, _) => {
+                let op = if reg >= 4 { reg ^ 1 } else { reg };
+                let src = self.st(0, &mut exc);
+                self.arithmetic(op, i, src, &mut exc);
+                if opcode == 0xDE && self.may_store(exc) {
+                    self.pop();
+                }
+            },
+
+            // FLD ST(i)
+            (0xD9, 0) => {
+                let value = self.st(i, &mut exc);
+                self.load(value, &mut exc);
+            },
+
+            // FXCH, and its undocumented DD alias
+            (0xD9, 1) | (0xDD, 1) => {
+                let a = self.st(0, &mut exc);
+                let b = self.st(i, &mut exc);
+                if self.may_store(exc) {
+                    self.set_st(0, b);
+                    self.set_st(i, a);
+                }
+            },
+
+            // FNOP
+            (0xD9, 2) if i == 0 => {},
+
+            // FST ST(i) and FSTP ST(i). D9 D8+i is an undocumented alias
+            // of FSTP.
+            (0xDD, 2) | (0xDD, 3) | (0xD9, 3) => {
+                let value = self.st(0, &mut exc);
+                if self.may_store(exc) {
+                    self.set_st(i, value);
+                    if reg == 3 {
+                        self.pop();
+                    }
+                }
+            },
+
+            // FFREE
+            (0xDD, 0) => self.set_tag(i, TAG_EMPTY),
+
+            (0xD9, 4...7) => self.function(reg, i, &env, &mut exc),
+
+            (0xDB, 4) => match i {
+                // FENI
+                0 => self.control = self.control & !INTERRUPT_MASK,
+                // FDISI
+                1 => self.control = self.control | INTERRUPT_MASK,
+                // FCLEX
+                2 => {
+                    self.status = self.status & !(EXCEPTION_MASKS | INTERRUPT_REQUEST);
+                    self.error_pending = false;
+                },
+                // FINIT
+                3 => self.reset(),
+                _ => exc = INVALID,
+            },
+
+            _ => exc = INVALID,
+        }
+
+        self.finish(exc)
+    }
+
+    /// The D9 E0-FF constant, transcendental and stack-control functions
+    fn function(&mut self, reg: u8, i: uint, env: &Env, exc: &mut Word) {
+        match (reg, i) {
+            // FCHS
+            (4, 0) => {
+                let value = self.st(0, exc);
+                self.set_st(0, value.negate());
+            },
+            // FABS
+            (4, 1) => {
+                let value = self.st(0, exc);
+                self.set_st(0, value.abs());
+            },
+            // FTST
+            (4, 4) => {
+                let value = self.st(0, exc);
+                self.compare(value, f80::zero(false), exc);
+            },
+            // FXAM
+            (4, 5) => self.examine(),
+
+            // FLD1, FLDL2T, FLDL2E, FLDPI, FLDLG2, FLDLN2, FLDZ
+            (5, 0) => self.load(f80::ONE, exc),
+            (5, 1) => self.load(f80::L2T, exc),
+            (5, 2) => self.load(f80::L2E, exc),
+            (5, 3) => self.load(f80::PI, exc),
+            (5, 4) => self.load(f80::LG2, exc),
+            (5, 5) => self.load(f80::LN2, exc),
+            (5, 6) => self.load(f80::zero(false), exc),
+
+            // F2XM1
+            (6, 0) => {
+                let value = self.st(0, exc);
+                let result = match value.class() {
+                    Class::NaN => f80::add(value, value, env, exc),
+                    _ => f80::f2xm1(value, exc),
+                };
+                self.set_st(0, result);
+            },
+            // FYL2X
+            (6, 1) => {
+                let x = self.st(0, exc);
+                let y = self.st(1, exc);
+                let result = match x.class() {
+                    _ if x.class() == Class::NaN || y.class() == Class::NaN => {
+                        f80::add(x, y, env, exc)
+                    },
+                    Class::Zero => {
+                        *exc = *exc | ZERO_DIVIDE;
+                        f80::infinity(!y.sign)
+                    },
+                    _ if x.sign => {
+                        *exc = *exc | INVALID;
+                        f80::indefinite()
+                    },
+                    Class::Infinity => f80::mul(x, y, env, exc),
+                    _ => {
+                        let log = f80::log2(x, exc);
+                        f80::mul(y, log, env, exc)
+                    },
+                };
+                if self.may_store(*exc) {
+                    self.pop();
+                    self.set_st(0, result);
+                }
+            },
+            // FPTAN
+            (6, 2) => {
+                let value = self.st(0, exc);
+                let (y, x) = match value.class() {
+                    Class::NaN | Class::Infinity => {
+                        *exc = *exc | INVALID;
+                        (f80::indefinite(), f80::indefinite())
+                    },
+                    _ => f80::tan_parts(value, exc),
+                };
+                if self.may_store(*exc) {
+                    self.set_st(0, y);
+                    self.push(x, exc);
+                }
+            },
+            // FPATAN
+            (6, 3) => {
+                let x = self.st(0, exc);
+                let y = self.st(1, exc);
+                let result = if x.class() == Class::NaN || y.class() == Class::NaN {
+                    f80::add(x, y, env, exc)
+                } else {
+                    f80::atan2(y, x, exc)
+                };
+                if self.may_store(*exc) {
+                    self.pop();
+                    self.set_st(0, result);
+                }
+            },
+            // FXTRACT
+            (6, 4) => {
+                let value = self.st(0, exc);
+                let (exponent, significand) = match value.class() {
+                    Class::NaN => (value, value),
+                    Class::Infinity => (f80::infinity(false), value),
+                    Class::Zero => {
+                        *exc = *exc | ZERO_DIVIDE;
+                        (f80::infinity(true), value)
+                    },
+                    _ => f80::extract(&value),
+                };
+                if self.may_store(*exc) {
+                    self.set_st(0, exponent);
+                    self.push(significand, exc);
+                }
+            },
+            // FDECSTP
+            (6, 6) => self.top = (self.top + 7) & 7,
+            // FINCSTP
+            (6, 7) => self.top = (self.top + 1) & 7,
+
+            // FPREM
+            (7, 0) => {
+                let a = self.st(0, exc);
+                let b = self.st(1, exc);
+                let (remainder, quotient, complete) = f80::partial_remainder(a, b, exc);
+                if self.may_store(*exc) {
+                    self.set_st(0, remainder);
+                    let condition = if complete {
+                        (if quotient & 4 != 0 { C0 } else { 0 }) |
+                        (if quotient & 2 != 0 { C3 } else { 0 }) |
+                        (if quotient & 1 != 0 { C1 } else { 0 })
+                    } else {
+                        C2
+                    };
+                    self.set_condition(condition);
+                }
+            },
+            // FYL2XP1
+            (7, 1) => {
+                let x = self.st(0, exc);
+                let y = self.st(1, exc);
+                let result = if x.class() == Class::NaN || y.class() == Class::NaN {
+                    f80::add(x, y, env, exc)
+                } else {
+                    let log = f80::log2_1p(x, exc);
+                    f80::mul(y, log, env, exc)
+                };
+                if self.may_store(*exc) {
+                    self.pop();
+                    self.set_st(0, result);
+                }
+            },
+            // FSQRT
+            (7, 2) => {
+                let value = self.st(0, exc);
+                let result = f80::sqrt(value, env, exc);
+                if self.may_store(*exc) {
+                    self.set_st(0, result);
+                }
+            },
+            // FRNDINT
+            (7, 4) => {
+                let value = self.st(0, exc);
+                let result = f80::round_to_integer(value, env.rounding, exc);
+                if self.may_store(*exc) {
+                    self.set_st(0, result);
+                }
+            },
+            // FSCALE
+            (7, 5) => {
+                let value = self.st(0, exc);
+                let factor = self.st(1, exc);
+                let result = if factor.class() == Class::NaN {
+                    f80::add(value, factor, env, exc)
+                } else {
+                    let mut scratch: Word = 0;
+                    match f80::to_integer(&factor, Rounding::Chop, &mut scratch) {
+                        Some((sign, magnitude)) => {
+                            // Anything this large over- or underflows anyway
+                            let n = if magnitude > 0x10000 { 0x10000 } else { magnitude as i32 };
+                            f80::scale(value, if sign { -n } else { n }, env, exc)
+                        },
+                        None => {
+                            *exc = *exc | INVALID;
+                            f80::indefinite()
+                        },
+                    }
+                };
+                if self.may_store(*exc) {
+                    self.set_st(0, result);
+                }
+            },
+
+            _ => *exc = *exc | INVALID,
+        }
+    }
+}
+
+
+fn read_mem(cs: &CpuState, seg: SegReg, addr: Word, len: uint) -> u64 {
+    let mut val: u64 = 0;
+    for i in range(0, len).rev() {
+        val = (val << 8) | cs.getmem(&seg, addr + i as Word) as u64;
+    }
+    val
+}
+
+fn write_mem(cs: &mut CpuState, seg: SegReg, addr: Word, len: uint, val: u64) {
+    for i in range(0, len) {
+        cs.setmem(&seg, addr + i as Word, (val >> (8 * i)) as Byte);
+    }
+}
+
+fn read_extended(cs: &CpuState, seg: SegReg, addr: Word) -> F80 {
+    let sign_exponent = cs.getmem16(&seg, addr + 8);
+    F80 {
+        sign: sign_exponent & 0x8000 != 0,
+        exponent: sign_exponent & 0x7FFF,
+        mantissa: read_mem(cs, seg, addr, 8),
+    }
+}
+
+fn write_extended(cs: &mut CpuState, seg: SegReg, addr: Word, value: &F80) {
+    let sign = if value.sign { 0x8000 } else { 0 };
+    write_mem(cs, seg, addr, 8, value.mantissa);
+    cs.setmem16(&seg, addr + 8, sign | value.exponent);
+}
+
+fn read_env(cs: &CpuState, seg: SegReg, addr: Word) -> [Word; 7] {
+    let mut env = [0u16; 7];
+    for i in range(0u, 7) {
+        env[i] = cs.getmem16(&seg, addr + (i * 2) as Word);
+    }
+    env
+}
+
+fn write_env(cs: &mut CpuState, seg: SegReg, addr: Word, env: &[Word; 7]) {
+    for i in range(0u, 7) {
+        cs.setmem16(&seg, addr + (i * 2) as Word, env[i]);
+    }
+}
+
+/// Memory-operand forms (ModR/M mod != 11)
+fn memory_op(cs: &mut CpuState, opcode: Byte, reg: u8, seg: SegReg, addr: Word) -> bool {
+    let mut exc: Word = 0;
+
+    match (opcode, reg) {
+        // FADD..FDIVR with a short real, short integer, long real or
+        // word integer operand
+        (0xD8, _) | (0xDA, _) | (0xDC, _) | (0xDE, _) => {
+            let src = match opcode {
+                0xD8 => f80::from_bits(read_mem(cs, seg, addr, 4), &f80::SINGLE, &mut exc),
+                0xDA => f80::from_i64(read_mem(cs, seg, addr, 4) as u32 as i32 as i64),
+                0xDC => f80::from_bits(read_mem(cs, seg, addr, 8), &f80::DOUBLE, &mut exc),
+                _ => f80::from_i64(read_mem(cs, seg, addr, 2) as u16 as i16 as i64),
+            };
+            cs.fpu().arithmetic(reg, 0, src, &mut exc);
+        },
+
+        // FLD
+        (0xD9, 0) => {
+            let value = f80::from_bits(read_mem(cs, seg, addr, 4), &f80::SINGLE, &mut exc);
+            cs.fpu().load(value, &mut exc);
+        },
+        (0xDD, 0) => {
+            let value = f80::from_bits(read_mem(cs, seg, addr, 8), &f80::DOUBLE, &mut exc);
+            cs.fpu().load(value, &mut exc);
+        },
+        (0xDB, 5) => {
+            let value = read_extended(cs, seg, addr);
+            cs.fpu().load(value, &mut exc);
+        },
+
+        // FST, FSTP
+        (0xD9, 2) | (0xD9, 3) => {
+            match cs.fpu().store_real(&f80::SINGLE, reg == 3, &mut exc) {
+                Some(bits) => write_mem(cs, seg, addr, 4, bits),
+                None => {},
+            }
+        },
+        (0xDD, 2) | (0xDD, 3) => {
+            match cs.fpu().store_real(&f80::DOUBLE, reg == 3, &mut exc) {
+                Some(bits) => write_mem(cs, seg, addr, 8, bits),
+                None => {},
+            }
+        },
+        (0xDB, 7) => {
+            match cs.fpu().store_extended(true, &mut exc) {
+                Some(value) => write_extended(cs, seg, addr, &value),
+                None => {},
+            }
+        },
+
+        // FILD
+        (0xDF, 0) => {
+            let value = f80::from_i64(read_mem(cs, seg, addr, 2) as u16 as i16 as i64);
+            cs.fpu().load(value, &mut exc);
+        },
+        (0xDB, 0) => {
+            let value = f80::from_i64(read_mem(cs, seg, addr, 4) as u32 as i32 as i64);
+            cs.fpu().load(value, &mut exc);
+        },
+        (0xDF, 5) => {
+            let value = f80::from_i64(read_mem(cs, seg, addr, 8) as i64);
+            cs.fpu().load(value, &mut exc);
+        },
+
+        // FIST, FISTP
+        (0xDF, 2) | (0xDF, 3) => {
+            match cs.fpu().store_int(16, reg == 3, &mut exc) {
+                Some(int) => write_mem(cs, seg, addr, 2, int),
+                None => {},
+            }
+        },
+        (0xDB, 2) | (0xDB, 3) => {
+            match cs.fpu().store_int(32, reg == 3, &mut exc) {
+                Some(int) => write_mem(cs, seg, addr, 4, int),
+                None => {},
+            }
+        },
+        (0xDF, 7) => {
+            match cs.fpu().store_int(64, true, &mut exc) {
+                Some(int) => write_mem(cs, seg, addr, 8, int),
+                None => {},
+            }
+        },
+
+        // FBLD
+        (0xDF, 4) => {
+            let mut bcd = [0u8; 10];
+            for i in range(0u, 10) {
+                bcd[i] = cs.getmem(&seg, addr + i as Word);
+            }
+            cs.fpu().load(f80::from_bcd(&bcd), &mut exc);
+        },
+        // FBSTP
+        (0xDF, 6) => {
+            match cs.fpu().store_bcd(&mut exc) {
+                Some(bcd) => {
+                    for i in range(0u, 10) {
+                        cs.setmem(&seg, addr + i as Word, bcd[i]);
+                    }
+                },
+                None => {},
+            }
+        },
+
+        // FLDENV, FSTENV. Storing the environment masks all exceptions.
+        (0xD9, 4) => {
+            let env = read_env(cs, seg, addr);
+            cs.fpu().load_env(&env);
+        },
+        (0xD9, 6) => {
+            let env = cs.fpu().store_env();
+            write_env(cs, seg, addr, &env);
+            let fpu = cs.fpu();
+            fpu.control = fpu.control | EXCEPTION_MASKS;
+        },
+
+        // FLDCW, FSTCW, FSTSW
+        (0xD9, 5) => {
+            let control = cs.getmem16(&seg, addr);
+            cs.fpu().control = control;
+        },
+        (0xD9, 7) => {
+            let control = cs.fpu().control_word();
+            cs.setmem16(&seg, addr, control);
+        },
+        (0xDD, 7) => {
+            let status = cs.fpu().status_word();
+            cs.setmem16(&seg, addr, status);
+        },
+
+        // FRSTOR, FSAVE: the environment followed by ST(0)-ST(7)
+        (0xDD, 4) => {
+            let env = read_env(cs, seg, addr);
+            cs.fpu().load_env(&env);
+            for i in range(0u, 8) {
+                let value = read_extended(cs, seg, addr + 14 + (i * 10) as Word);
+                let fpu = cs.fpu();
+                let phys = fpu.physical(i);
+                fpu.regs[phys] = value;
+            }
+        },
+        (0xDD, 6) => {
+            let env = cs.fpu().store_env();
+            write_env(cs, seg, addr, &env);
+            for i in range(0u, 8) {
+                let value = {
+                    let fpu = cs.fpu();
+                    fpu.regs[fpu.physical(i)]
+                };
+                write_extended(cs, seg, addr + 14 + (i * 10) as Word, &value);
+            }
+            cs.fpu().reset();
+        },
+
+        _ => exc = INVALID,
+    }
+
+    cs.fpu().finish(exc)
+}
+
+/// Control instructions don't update the saved instruction and operand
+/// pointers, so exception handlers can still find the faulting one
+fn is_control(opcode: Byte, modrm: Byte) -> bool {
+    let reg = (modrm >> 3) & 7;
+    if modrm >= 0xC0 {
+        return opcode == 0xDB && reg == 4;
+    }
+    match (opcode, reg) {
+        (0xD9, 4...7) | (0xDD, 4) | (0xDD, 6) | (0xDD, 7) => true,
+        _ => false,
+    }
+}
+
+/// Execute an ESC instruction (D8-DF) on the coprocessor. Instructions
+/// complete before the CPU continues. An unmasked exception asserts the
+/// 8087's interrupt line, which is wired to NMI as on the IBM PC, the next
+/// time the CPU synchronises with the coprocessor: at the following FWAIT
+/// or ESC instruction.
+pub fn esc_opcode(cs: &mut CpuState, opcode: Byte) {
+    let opcode_ip = cs.getreg16(&Reg16::IP) - 1;
+    let modrm_byte = cs.getmem(&SegReg::CS, opcode_ip + 1);
+
+    // The handler returns to this instruction. Control instructions, which
+    // handlers use to examine and clear the exception, don't signal it.
+    if !is_control(opcode, modrm_byte) && cs.fpu().take_error() {
+        cs.restart_instruction();
+        cs.raise_nmi();
+        return;
+    }
+    let instr_ptr = CpuState::physical_address(cs.getseg(&SegReg::CS), cs.instruction_ip());
+    let (reg, effective, _) = modrm::read_modrm(cs, false);
+
+    let raised = match effective {
+        Operand::MemoryAddress(seg, addr) => {
+            if !is_control(opcode, modrm_byte) {
+                let operand_ptr = CpuState::physical_address(cs.getseg(&seg), addr);
+                cs.fpu().record(instr_ptr, opcode, modrm_byte, operand_ptr);
+            }
+            memory_op(cs, opcode, reg, seg, addr)
+        },
+        _ => {
+            let fpu = cs.fpu();
+            if !is_control(opcode, modrm_byte) {
+                fpu.record(instr_ptr, opcode, modrm_byte, 0);
+            }
+            fpu.register_op(opcode, reg, (modrm_byte & 7) as uint)
+        },
+    };
+
+    if raised {
+        cs.fpu().error_pending = true;
+    }
+}
+
+/// FWAIT. The coprocessor finishes every instruction before the CPU
+/// continues, so there is never anything to wait for, but an unmasked
+/// exception left by the last instruction is signalled here.
+pub fn fwait(cs: &mut CpuState) {
+    if cs.fpu().take_error() {
+        cs.raise_nmi();
+    }
+}
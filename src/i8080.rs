@@ -0,0 +1,488 @@
+use std::num::ToPrimitive;
+use cstate::{CpuState, Reg8, Reg16, SegReg};
+use datatypes::{Byte, Word};
+use operation::{op8, op8_dry, Transform8};
+use operand::Operand;
+use specialops;
+use tf;
+
+
+// Decoder for the V20's 8080 emulation mode. The 8080 registers live in
+// the native ones: A = AL, B = CH, C = CL, D = DH, E = DL, H = BH, L = BL,
+// SP = BP and PC = IP, so BC, DE and HL are CX, DX and BX. Instructions
+// are fetched from CS:PC; data and the stack are in DS.
+
+
+/// Execute one 8080 instruction
+pub fn step(cs: &mut CpuState) {
+    let opcode = cs.read();
+    cs.begin_instruction();
+
+    match opcode {
+        0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => {},
+
+        // LXI rp, d16
+        0x01 | 0x11 | 0x21 | 0x31 => {
+            let val = cs.read16();
+            cs.setreg16(&pair(opcode >> 4), val);
+        },
+
+        // STAX, LDAX
+        0x02 | 0x12 => {
+            let addr = cs.getreg16(&pair(opcode >> 4));
+            op8(cs, Operand::MemoryAddress(SegReg::DS, addr), Operand::Reg8(Reg8::AL), tf::noop8);
+        },
+        0x0A | 0x1A => {
+            let addr = cs.getreg16(&pair(opcode >> 4));
+            op8(cs, Operand::Reg8(Reg8::AL), Operand::MemoryAddress(SegReg::DS, addr), tf::noop8);
+        },
+
+        // SHLD, LHLD, STA, LDA
+        0x22 => {
+            let addr = cs.read16();
+            let hl = cs.getreg16(&Reg16::BX);
+            cs.setmem16(&SegReg::DS, addr, hl);
+        },
+        0x2A => {
+            let addr = cs.read16();
+            let val = cs.getmem16(&SegReg::DS, addr);
+            cs.setreg16(&Reg16::BX, val);
+        },
+        0x32 => {
+            let addr = cs.read16();
+            op8(cs, Operand::MemoryAddress(SegReg::DS, addr), Operand::Reg8(Reg8::AL), tf::noop8);
+        },
+        0x3A => {
+            let addr = cs.read16();
+            op8(cs, Operand::Reg8(Reg8::AL), Operand::MemoryAddress(SegReg::DS, addr), tf::noop8);
+        },
+
+        // INX, DCX
+        0x03 | 0x13 | 0x23 | 0x33 => {
+            let reg = pair(opcode >> 4);
+            let val = cs.getreg16(&reg);
+            cs.setreg16(&reg, val + 1);
+        },
+        0x0B | 0x1B | 0x2B | 0x3B => {
+            let reg = pair(opcode >> 4);
+            let val = cs.getreg16(&reg);
+            cs.setreg16(&reg, val - 1);
+        },
+
+        // INR, DCR, MVI
+        0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+            let dest = register(cs, opcode >> 3);
+            op8(cs, dest, Operand::RawByte(1), tf::inc8);
+        },
+        0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+            let dest = register(cs, opcode >> 3);
+            op8(cs, dest, Operand::RawByte(1), tf::dec8);
+        },
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+            let immediate = Operand::RawByte(cs.read());
+            let dest = register(cs, opcode >> 3);
+            op8(cs, dest, immediate, tf::noop8);
+        },
+
+        // RLC, RRC, RAL, RAR
+        0x07 => op8(cs, Operand::Reg8(Reg8::AL), Operand::RawByte(1), tf::rol8),
+        0x0F => op8(cs, Operand::Reg8(Reg8::AL), Operand::RawByte(1), tf::ror8),
+        0x17 => op8(cs, Operand::Reg8(Reg8::AL), Operand::RawByte(1), tf::rcl8),
+        0x1F => op8(cs, Operand::Reg8(Reg8::AL), Operand::RawByte(1), tf::rcr8),
+
+        // DAD rp: only CY is affected
+        0x09 | 0x19 | 0x29 | 0x39 => {
+            let hl = cs.getreg16(&Reg16::BX).to_u32().unwrap();
+            let val = cs.getreg16(&pair(opcode >> 4)).to_u32().unwrap();
+            let sum = hl + val;
+            cs.setreg16(&Reg16::BX, sum as Word);
+
+            let mut flags = cs.get_flags();
+            flags.carry = sum > 0xFFFF;
+            cs.set_flags(flags);
+        },
+
+        0x27 => specialops::daa(cs),
+        0x2F => op8(cs, Operand::Reg8(Reg8::AL), Operand::RawByte(0), tf::not8),
+        0x37 => specialops::stc(cs),
+        0x3F => specialops::cmc(cs),
+
+        0x76 => cs.halt(),
+
+        // MOV r, r
+        0x40...0x7F => {
+            let src = register(cs, opcode);
+            let dest = register(cs, opcode >> 3);
+            op8(cs, dest, src, tf::noop8);
+        },
+
+        // ALU A, r
+        0x80...0xBF => {
+            let src = register(cs, opcode);
+            alu(cs, opcode >> 3, src);
+        },
+
+        // Conditional return, jump and call
+        0xC0 | 0xC8 | 0xD0 | 0xD8 | 0xE0 | 0xE8 | 0xF0 | 0xF8 => {
+            if condition(cs, opcode >> 3) {
+                let target = pop(cs);
+                cs.setreg16(&Reg16::IP, target);
+            }
+        },
+        0xC2 | 0xCA | 0xD2 | 0xDA | 0xE2 | 0xEA | 0xF2 | 0xFA => {
+            let target = cs.read16();
+            if condition(cs, opcode >> 3) {
+                cs.setreg16(&Reg16::IP, target);
+            }
+        },
+        0xC4 | 0xCC | 0xD4 | 0xDC | 0xE4 | 0xEC | 0xF4 | 0xFC => {
+            let target = cs.read16();
+            if condition(cs, opcode >> 3) {
+                call(cs, target);
+            }
+        },
+
+        // POP, PUSH
+        0xC1 | 0xD1 | 0xE1 => {
+            let val = pop(cs);
+            cs.setreg16(&pair(opcode >> 4), val);
+        },
+        0xF1 => {
+            let val = pop(cs);
+            let flags = (cs.get_flags_word() & 0xFF00) | (val & 0x00FF);
+            cs.set_flags_word(flags);
+            cs.setreg8(&Reg8::AL, (val >> 8) as Byte);
+        },
+        0xC5 | 0xD5 | 0xE5 => {
+            let val = cs.getreg16(&pair(opcode >> 4));
+            push(cs, val);
+        },
+        0xF5 => {
+            let a = cs.getreg8(&Reg8::AL).to_u16().unwrap();
+            let val = (a << 8) | (cs.get_flags_word() & 0x00FF);
+            push(cs, val);
+        },
+
+        // ALU A, d8
+        0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => {
+            let immediate = Operand::RawByte(cs.read());
+            alu(cs, opcode >> 3, immediate);
+        },
+
+        // RST n
+        0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+            call(cs, (opcode & 0x38).to_u16().unwrap());
+        },
+
+        // JMP, RET, CALL and their undocumented aliases. ED is taken by
+        // the V20's CALLN/RETEM.
+        0xC3 | 0xCB => {
+            let target = cs.read16();
+            cs.setreg16(&Reg16::IP, target);
+        },
+        0xC9 | 0xD9 => {
+            let target = pop(cs);
+            cs.setreg16(&Reg16::IP, target);
+        },
+        0xCD | 0xDD | 0xFD => {
+            let target = cs.read16();
+            call(cs, target);
+        },
+
+        0xED => match cs.read() {
+            // CALLN: call a native interrupt handler, which returns to
+            // emulation mode with IRET
+            0xED => {
+                let vector = cs.read();
+                cs.interrupt(vector);
+            },
+            // RETEM: return from BRKEM to native mode
+            0xFD => specialops::iret(cs),
+            // NEC leaves the other ED xx encodings undefined. They are
+            // run as two-byte NOPs so an 8080 program can't stop the
+            // emulator.
+            _ => {},
+        },
+
+        // OUT p, IN p
+        0xD3 => {
+            let port = cs.read().to_u16().unwrap();
+            let val = cs.getreg8(&Reg8::AL);
+            cs.port_out8(port, val);
+        },
+        0xDB => {
+            let port = cs.read().to_u16().unwrap();
+            let val = cs.port_in8(port);
+            cs.setreg8(&Reg8::AL, val);
+        },
+
+        // XTHL, PCHL, XCHG, SPHL
+        0xE3 => {
+            let sp = cs.getreg16(&Reg16::BP);
+            let top = cs.getmem16(&SegReg::DS, sp);
+            let hl = cs.getreg16(&Reg16::BX);
+            cs.setmem16(&SegReg::DS, sp, hl);
+            cs.setreg16(&Reg16::BX, top);
+        },
+        0xE9 => {
+            let hl = cs.getreg16(&Reg16::BX);
+            cs.setreg16(&Reg16::IP, hl);
+        },
+        0xEB => {
+            let de = cs.getreg16(&Reg16::DX);
+            let hl = cs.getreg16(&Reg16::BX);
+            cs.setreg16(&Reg16::DX, hl);
+            cs.setreg16(&Reg16::BX, de);
+        },
+        0xF9 => {
+            let hl = cs.getreg16(&Reg16::BX);
+            cs.setreg16(&Reg16::BP, hl);
+        },
+
+        // DI, EI
+        0xF3 => specialops::cli(cs),
+        0xFB => specialops::sti(cs),
+
+        _ => panic!("Invalid 8080 opcode: 0x{:X}", opcode),
+    };
+}
+
+/// The 8080 register in bits 0-2 of `code': B, C, D, E, H, L, M or A.
+/// M is the byte at HL.
+fn register(cs: &CpuState, code: Byte) -> Operand {
+    match code & 0b111 {
+        0b000 => Operand::Reg8(Reg8::CH),
+        0b001 => Operand::Reg8(Reg8::CL),
+        0b010 => Operand::Reg8(Reg8::DH),
+        0b011 => Operand::Reg8(Reg8::DL),
+        0b100 => Operand::Reg8(Reg8::BH),
+        0b101 => Operand::Reg8(Reg8::BL),
+        0b110 => Operand::MemoryAddress(SegReg::DS, cs.getreg16(&Reg16::BX)),
+        0b111 => Operand::Reg8(Reg8::AL),
+        _ => panic!("Invalid 8080 register"),
+    }
+}
+
+/// The 8080 register pair in bits 0-1 of `code': BC, DE, HL or SP
+fn pair(code: Byte) -> Reg16 {
+    match code & 0b11 {
+        0b00 => Reg16::CX,
+        0b01 => Reg16::DX,
+        0b10 => Reg16::BX,
+        0b11 => Reg16::BP,
+        _ => panic!("Invalid 8080 register pair"),
+    }
+}
+
+/// The eight 8080 ALU operations, in the order of bits 3-5 of the opcode:
+/// ADD, ADC, SUB, SBB, ANA, XRA, ORA and CMP
+fn alu(cs: &mut CpuState, index: Byte, src: Operand) {
+    let dest = Operand::Reg8(Reg8::AL);
+    let tf8 = match index & 0b111 {
+        0b000 => tf::add8 as Transform8,
+        0b001 => tf::adc8 as Transform8,
+        0b010 => tf::sub8 as Transform8,
+        0b011 => tf::sbb8 as Transform8,
+        0b100 => tf::and8 as Transform8,
+        0b101 => tf::xor8 as Transform8,
+        0b110 => tf::or8 as Transform8,
+        _ => return op8_dry(cs, dest, src, tf::sub8),
+    };
+    op8(cs, dest, src, tf8);
+}
+
+/// The 8080 condition in bits 0-2 of `code': NZ, Z, NC, C, PO, PE, P or M
+fn condition(cs: &CpuState, code: Byte) -> bool {
+    let cc = match code & 0b111 {
+        0b000 => 0x5,
+        0b001 => 0x4,
+        0b010 => 0x3,
+        0b011 => 0x2,
+        0b100 => 0xB,
+        0b101 => 0xA,
+        0b110 => 0x9,
+        _ => 0x8,
+    };
+    cs.condition(cc)
+}
+
+fn push(cs: &mut CpuState, val: Word) {
+    let sp = cs.getreg16(&Reg16::BP) - 2;
+    cs.setreg16(&Reg16::BP, sp);
+    cs.setmem16(&SegReg::DS, sp, val);
+}
+
+fn pop(cs: &mut CpuState) -> Word {
+    let sp = cs.getreg16(&Reg16::BP);
+    cs.setreg16(&Reg16::BP, sp + 2);
+    cs.getmem16(&SegReg::DS, sp)
+}
+
+fn call(cs: &mut CpuState, target: Word) {
+    let pc = cs.getreg16(&Reg16::IP);
+    push(cs, pc);
+    cs.setreg16(&Reg16::IP, target);
+}
+
+
+#[cfg(test)]
+mod test {
+    use cstate::{CpuState, CpuModel, Reg8, Reg16, SegReg};
+    use opcodes;
+    use super::{step, condition};
+
+    /// A CPU with `code' loaded at 0000:0100, ready to execute it
+    fn with_code(code: &[u8]) -> CpuState {
+        let mut cs = CpuState::new();
+        load(&mut cs, 0x100, code);
+        cs.setreg16(&Reg16::IP, 0x100);
+        cs
+    }
+
+    fn load(cs: &mut CpuState, addr: u16, code: &[u8]) {
+        for (i, byte) in code.iter().enumerate() {
+            cs.setmem(&SegReg::CS, addr + i as u16, *byte);
+        }
+    }
+
+    /// Point interrupt vector `vector' at 0000:`addr'
+    fn set_vector(cs: &mut CpuState, vector: u16, addr: u16) {
+        cs.setmem16(&SegReg::CS, vector * 4, addr);
+        cs.setmem16(&SegReg::CS, vector * 4 + 2, 0);
+    }
+
+    fn run(cs: &mut CpuState, count: uint) {
+        for _ in range(0, count) {
+            step(cs);
+        }
+    }
+
+    #[test]
+    fn register_mapping() {
+        // MVI B, C, D, E, H, L, A, then MVI M with HL = 0x5566
+        let mut cs = with_code(&[0x06, 0x11, 0x0E, 0x22, 0x16, 0x33, 0x1E, 0x44,
+                                 0x26, 0x55, 0x2E, 0x66, 0x3E, 0x77, 0x36, 0x88]);
+        run(&mut cs, 8);
+        assert_eq!(cs.getreg8(&Reg8::CH), 0x11);
+        assert_eq!(cs.getreg8(&Reg8::CL), 0x22);
+        assert_eq!(cs.getreg8(&Reg8::DH), 0x33);
+        assert_eq!(cs.getreg8(&Reg8::DL), 0x44);
+        assert_eq!(cs.getreg8(&Reg8::BH), 0x55);
+        assert_eq!(cs.getreg8(&Reg8::BL), 0x66);
+        assert_eq!(cs.getreg8(&Reg8::AL), 0x77);
+        assert_eq!(cs.getmem(&SegReg::DS, 0x5566), 0x88);
+
+        // LXI B, D, H, SP
+        let mut cs = with_code(&[0x01, 0x34, 0x12, 0x11, 0x78, 0x56,
+                                 0x21, 0xBC, 0x9A, 0x31, 0xF0, 0xDE]);
+        run(&mut cs, 4);
+        assert_eq!(cs.getreg16(&Reg16::CX), 0x1234);
+        assert_eq!(cs.getreg16(&Reg16::DX), 0x5678);
+        assert_eq!(cs.getreg16(&Reg16::BX), 0x9ABC);
+        assert_eq!(cs.getreg16(&Reg16::BP), 0xDEF0);
+        assert_eq!(cs.getreg16(&Reg16::SP), 0x100);
+    }
+
+    #[test]
+    fn push_pop_psw() {
+        // The flag byte is S Z 0 AC 0 P 1 CY, with A above it
+        let mut cs = with_code(&[0xF5, 0xF1]);
+        cs.setreg8(&Reg8::AL, 0x12);
+        cs.set_flags_word(0x0041);
+        cs.setreg16(&Reg16::BP, 0x200);
+        run(&mut cs, 1);
+        assert_eq!(cs.getreg16(&Reg16::BP), 0x1FE);
+        assert_eq!(cs.getmem(&SegReg::DS, 0x1FE), 0x43);
+        assert_eq!(cs.getmem(&SegReg::DS, 0x1FF), 0x12);
+
+        cs.setreg8(&Reg8::AL, 0);
+        cs.set_flags_word(0);
+        cs.setmem(&SegReg::DS, 0x1FE, 0xC4);
+        run(&mut cs, 1);
+        assert_eq!(cs.getreg16(&Reg16::BP), 0x200);
+        assert_eq!(cs.getreg8(&Reg8::AL), 0x12);
+        assert_eq!(cs.get_flags_word() & 0xFF, 0xC6);
+    }
+
+    #[test]
+    fn dad_sets_only_carry() {
+        // DAD D; DAD H
+        let mut cs = with_code(&[0x19, 0x29]);
+        cs.setreg16(&Reg16::BX, 0xFFFF);
+        cs.setreg16(&Reg16::DX, 0x0002);
+        cs.set_flags_word(0x0040);
+        run(&mut cs, 1);
+        assert_eq!(cs.getreg16(&Reg16::BX), 0x0001);
+        assert!(cs.carry());
+        assert!(cs.zero());
+
+        run(&mut cs, 1);
+        assert_eq!(cs.getreg16(&Reg16::BX), 0x0002);
+        assert!(!cs.carry());
+        assert!(cs.zero());
+    }
+
+    #[test]
+    fn conditions() {
+        // NZ, Z, NC, C, PO, PE, P, M
+        let mut cs = CpuState::new();
+        cs.set_flags_word(0);
+        let clear = [true, false, true, false, true, false, true, false];
+        for (code, &expected) in clear.iter().enumerate() {
+            assert_eq!(condition(&cs, code as u8), expected);
+        }
+
+        // S, Z, P and CY set
+        cs.set_flags_word(0x00C5);
+        for (code, &expected) in clear.iter().enumerate() {
+            assert_eq!(condition(&cs, code as u8), !expected);
+        }
+    }
+
+    #[test]
+    fn brkem_retem() {
+        // BRKEM 0x80 calls the 8080 program at 0000:0300, which returns
+        // with RETEM
+        let mut cs = with_code(&[0x0F, 0xFF, 0x80]);
+        cs.set_model(CpuModel::V20);
+        cs.setreg16(&Reg16::SP, 0x2000);
+        set_vector(&mut cs, 0x80, 0x300);
+        load(&mut cs, 0x300, &[0xED, 0xFD]);
+
+        opcodes::step(&mut cs);
+        assert!(cs.emulation_mode());
+        assert_eq!(cs.getreg16(&Reg16::IP), 0x300);
+        assert_eq!(cs.get_flags_word() & 0x8000, 0);
+        assert_eq!(cs.getmem16(&SegReg::SS, 0x1FFE) & 0x8000, 0x8000);
+
+        opcodes::step(&mut cs);
+        assert!(!cs.emulation_mode());
+        assert_eq!(cs.getreg16(&Reg16::IP), 0x103);
+        assert_eq!(cs.getreg16(&Reg16::SP), 0x2000);
+        assert_eq!(cs.get_flags_word() & 0x8000, 0x8000);
+    }
+
+    #[test]
+    fn calln_iret() {
+        // CALLN 0x84 calls the native handler at 0000:0400, whose IRET
+        // returns to emulation mode
+        let mut cs = CpuState::new();
+        cs.set_model(CpuModel::V20);
+        cs.set_emulation_mode(true);
+        cs.setreg16(&Reg16::SP, 0x2000);
+        load(&mut cs, 0x300, &[0xED, 0xED, 0x84]);
+        cs.setreg16(&Reg16::IP, 0x300);
+        set_vector(&mut cs, 0x84, 0x400);
+        load(&mut cs, 0x400, &[0xCF]);
+
+        opcodes::step(&mut cs);
+        assert!(!cs.emulation_mode());
+        assert_eq!(cs.getreg16(&Reg16::IP), 0x400);
+        assert_eq!(cs.getmem16(&SegReg::SS, 0x1FFE) & 0x8000, 0);
+
+        opcodes::step(&mut cs);
+        assert!(cs.emulation_mode());
+        assert_eq!(cs.getreg16(&Reg16::IP), 0x303);
+        assert_eq!(cs.getreg16(&Reg16::SP), 0x2000);
+    }
+}
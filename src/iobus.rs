@@ -0,0 +1,129 @@
+use std::io;
+use byteutils::{low8, high8, join8};
+use datatypes::{Byte, Word};
+
+
+/// A device attached to the I/O bus. Word accesses are made to even
+/// ports only; by default they are split into two byte accesses, low
+/// port first.
+pub trait IoDevice {
+    fn port_in(&mut self, port: Word) -> Byte;
+    fn port_out(&mut self, port: Word, val: Byte);
+
+    fn port_in16(&mut self, port: Word) -> Word {
+        let high_b = self.port_in(port);
+        let low_b = self.port_in(port + 1);
+        join8(low_b, high_b)
+    }
+
+    fn port_out16(&mut self, port: Word, val: Word) {
+        self.port_out(port, high8(val));
+        self.port_out(port + 1, low8(val));
+    }
+}
+
+/// The CPU's port I/O bus. `CpuState' splits a word access to an odd
+/// port into two byte accesses, low port first, as the 8088 does, so the
+/// word methods only see even ports.
+pub trait IoBus {
+    /// Attach `device' to ports `first' through `last' inclusive
+    fn register(&mut self, first: Word, last: Word, device: Box<IoDevice + 'static>);
+
+    fn port_in(&mut self, port: Word) -> Byte;
+    fn port_out(&mut self, port: Word, val: Byte);
+
+    fn port_in16(&mut self, port: Word) -> Word {
+        let high_b = self.port_in(port);
+        let low_b = self.port_in(port + 1);
+        join8(low_b, high_b)
+    }
+
+    fn port_out16(&mut self, port: Word, val: Word) {
+        self.port_out(port, high8(val));
+        self.port_out(port + 1, low8(val));
+    }
+}
+
+
+struct PortRange {
+    first: Word,
+    last: Word,
+    device: Box<IoDevice + 'static>,
+}
+
+/// The default bus. Each access goes to the first device registered for
+/// the port; a word access is only passed on whole if that device also
+/// claims the next port. Unclaimed ports are logged to stderr; reads from
+/// them return 0xFF, as from a floating bus, and writes are dropped.
+pub struct PortMap {
+    ranges: Vec<PortRange>,
+}
+
+impl PortMap {
+    pub fn new() -> PortMap {
+        PortMap {
+            ranges: Vec::new(),
+        }
+    }
+}
+
+impl IoBus for PortMap {
+    fn register(&mut self, first: Word, last: Word, device: Box<IoDevice + 'static>) {
+        self.ranges.push(PortRange {
+            first: first,
+            last: last,
+            device: device,
+        });
+    }
+
+    fn port_in(&mut self, port: Word) -> Byte {
+        for range in self.ranges.iter_mut() {
+            if port >= range.first && port <= range.last {
+                return range.device.port_in(port);
+            }
+        }
+
+        let _ = writeln!(&mut io::stderr(), "io: read from unclaimed port 0x{:0>4X}", port);
+        0xFF
+    }
+
+    fn port_out(&mut self, port: Word, val: Byte) {
+        for range in self.ranges.iter_mut() {
+            if port >= range.first && port <= range.last {
+                return range.device.port_out(port, val);
+            }
+        }
+
+        let _ = writeln!(&mut io::stderr(),
+                         "io: write of 0x{:0>2X} to unclaimed port 0x{:0>4X}", val, port);
+    }
+
+    fn port_in16(&mut self, port: Word) -> Word {
+        for range in self.ranges.iter_mut() {
+            if port >= range.first && port <= range.last {
+                if port < range.last {
+                    return range.device.port_in16(port);
+                }
+                break;
+            }
+        }
+
+        let high_b = self.port_in(port);
+        let low_b = self.port_in(port + 1);
+        join8(low_b, high_b)
+    }
+
+    fn port_out16(&mut self, port: Word, val: Word) {
+        for range in self.ranges.iter_mut() {
+            if port >= range.first && port <= range.last {
+                if port < range.last {
+                    return range.device.port_out16(port, val);
+                }
+                break;
+            }
+        }
+
+        self.port_out(port, high8(val));
+        self.port_out(port + 1, low8(val));
+    }
+}
@@ -1,35 +1,86 @@
 #![allow(unstable)]
 use std::os;
+use std::io;
+use std::io::timer;
+use std::time::Duration;
 mod byteutils;
 mod cstate;
 mod datatypes;
 mod debugger;
+mod f80;
+mod fpu;
+mod i8080;
+mod iobus;
 mod opcodes;
 mod operation;
 mod operand;
 mod modrm;
+mod necops;
 mod specialops;
+mod stringops;
 mod tf;
 
 
 fn main() {
     let argv = os::args();
     if argv.len() < 2 {
-        println!("Usage: {} <filename>", argv[0]);
+        println!("Usage: {} [--no-exit-on-hlt] [--strict] [--cpu=8086|186|v20] <filename>", argv[0]);
         return;
     }
-    let path = Path::new(&argv[1]);
+    let path = Path::new(&argv[argv.len() - 1]);
+    let exit_on_halt = !argv.iter().any(|arg| arg.as_slice() == "--no-exit-on-hlt");
+    let strict = argv.iter().any(|arg| arg.as_slice() == "--strict");
+    let mut model = cstate::CpuModel::I8086;
+    for arg in argv.iter() {
+        match arg.as_slice() {
+            "--cpu=8086" | "--cpu=8088" => model = cstate::CpuModel::I8086,
+            "--cpu=186" | "--cpu=188" => model = cstate::CpuModel::I80186,
+            "--cpu=v20" | "--cpu=v30" => model = cstate::CpuModel::V20,
+            _ => {},
+        }
+    }
 
     let mut cs = cstate::CpuState::new();
     cs.load_program(&path);
+    cs.set_exit_on_halt(exit_on_halt);
+    if strict {
+        cs.set_decode_mode(cstate::DecodeMode::Strict);
+    }
+    cs.set_model(model);
 
     loop {
-        let opcode = cs.read();
-        if opcode == 0xF4 {
-            debugger::dump_state(&cs);
-            debugger::dump_vram(&cs);
-            return;
+        opcodes::step(&mut cs);
+
+        match cs.invalid_opcode() {
+            Some(opcode) => {
+                let _ = writeln!(&mut io::stderr(), "Invalid opcode 0x{:0>2X} at {:0>4X}:{:0>4X}",
+                                 opcode,
+                                 cs.getseg(&cstate::SegReg::CS),
+                                 cs.getreg16(&cstate::Reg16::IP));
+                debugger::dump_state(&cs);
+                debugger::dump_vram(&cs);
+                return;
+            },
+            None => {},
+        }
+
+        if cs.halted() && !cs.interrupt_pending() {
+            // Only NMI can wake a CPU halted with IF clear, and nothing in
+            // this loop raises it
+            if !cs.interrupts_enabled() {
+                if !cs.exit_on_halt() {
+                    let _ = writeln!(&mut io::stderr(),
+                                     "Halted with interrupts disabled and no NMI source");
+                }
+                debugger::dump_state(&cs);
+                debugger::dump_vram(&cs);
+                return;
+            }
+
+            // Devices don't run on their own, so any device that raises
+            // interrupts must be driven from this loop. Don't spin a host
+            // core while the guest waits for one.
+            timer::sleep(Duration::milliseconds(1));
         }
-        opcodes::do_opcode(&mut cs, opcode);
     }
 }
@@ -1,4 +1,6 @@
-use cstate::{CpuState, Reg8, Reg16};
+use byteutils::sign_extend8;
+use cstate::{CpuState, Reg8, Reg16, SegReg};
+use datatypes::Word;
 use operand::Operand;
 
 
@@ -30,7 +32,11 @@ fn modrm_register(reg: u8, byte: bool) -> Operand {
             0b001 => Operand::Reg8(Reg8::CL),
             0b010 => Operand::Reg8(Reg8::DL),
             0b011 => Operand::Reg8(Reg8::BL),
-            _ => Operand::RawWord(65534), // FIXME
+            0b100 => Operand::Reg8(Reg8::AH),
+            0b101 => Operand::Reg8(Reg8::CH),
+            0b110 => Operand::Reg8(Reg8::DH),
+            0b111 => Operand::Reg8(Reg8::BH),
+            _ => panic!("Invalid ModR/M byte 1"),
         }
     } else {
         return match reg {
@@ -47,45 +53,63 @@ fn modrm_register(reg: u8, byte: bool) -> Operand {
     }
 }
 
+/// Decode the ModR/M reg field of MOV Sreg instructions (8C/8E). Values
+/// 4-7 don't name a segment register and give None.
+pub fn segment_register(reg: u8) -> Option<SegReg> {
+    match reg {
+        0b000 => Some(SegReg::ES),
+        0b001 => Some(SegReg::CS),
+        0b010 => Some(SegReg::SS),
+        0b011 => Some(SegReg::DS),
+        _ => None,
+    }
+}
+
+/// Compute the base offset selected by `rm', before any displacement,
+/// along with its segment: SS for BP-based forms, DS otherwise, unless
+/// a segment override prefix is in effect.
+/// rm = 0b110 is [BP] here; the mod = 00 direct address case is handled
+/// by the caller.
+fn modrm_base(cs: &CpuState, rm: u8) -> (SegReg, Word) {
+    let (seg, base) = match rm {
+        0b000 => (SegReg::DS, cs.getreg16(&Reg16::BX) + cs.getreg16(&Reg16::SI)),
+        0b001 => (SegReg::DS, cs.getreg16(&Reg16::BX) + cs.getreg16(&Reg16::DI)),
+        0b010 => (SegReg::SS, cs.getreg16(&Reg16::BP) + cs.getreg16(&Reg16::SI)),
+        0b011 => (SegReg::SS, cs.getreg16(&Reg16::BP) + cs.getreg16(&Reg16::DI)),
+        0b100 => (SegReg::DS, cs.getreg16(&Reg16::SI)),
+        0b101 => (SegReg::DS, cs.getreg16(&Reg16::DI)),
+        0b110 => (SegReg::SS, cs.getreg16(&Reg16::BP)),
+        0b111 => (SegReg::DS, cs.getreg16(&Reg16::BX)),
+        _ => panic!("Invalid ModR/M byte"),
+    };
+    (cs.segment(seg), base)
+}
+
 fn modrm_effective(cs: &mut CpuState, modbits: u8, rm: u8, byte_registers: bool) -> Operand {
-    match modbits {
+    let (seg, addr) = match modbits {
+        // [base], or a direct 16-bit address when rm = 0b110
         0b00 => match rm {
-            0b000 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::BX) + cs.getreg16(&Reg16::SI)
-            ),
-            0b001 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::BX) + cs.getreg16(&Reg16::DI)
-            ),
-            0b010 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::BP) + cs.getreg16(&Reg16::SI)
-            ),
-            0b011 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::BP) + cs.getreg16(&Reg16::DI)
-            ),
-            0b100 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::SI)
-            ),
-            0b101 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::DI)
-            ),
-            0b111 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::BX)
-            ),
-            0b110 => Operand::MemoryAddress(
-                cs.read16()
-            ),
-            _ => panic!("Invalid ModR/M byte"),
+            0b110 => {
+                let addr = cs.read16();
+                (cs.segment(SegReg::DS), addr)
+            },
+            _ => modrm_base(cs, rm),
         },
-        0b11 => modrm_register(rm, byte_registers),
-        0b10 => match rm {
-            0b111 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::BX) + cs.read16()
-            ),
-            0b101 => Operand::MemoryAddress(
-                cs.getreg16(&Reg16::DI) + cs.read16()
-            ),
-            _ => panic!("Not Implemented"),
+        // [base + disp8], with the displacement sign-extended
+        0b01 => {
+            let disp = sign_extend8(cs.read());
+            let (seg, base) = modrm_base(cs, rm);
+            (seg, base + disp)
         },
-        _ => panic!("Not Implemented"),
-    }
+        // [base + disp16]
+        0b10 => {
+            let disp = cs.read16();
+            let (seg, base) = modrm_base(cs, rm);
+            (seg, base + disp)
+        },
+        0b11 => return modrm_register(rm, byte_registers),
+        _ => panic!("Invalid ModR/M byte"),
+    };
+    cs.set_last_address(seg, addr);
+    Operand::MemoryAddress(seg, addr)
 }
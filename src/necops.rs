@@ -0,0 +1,306 @@
+use std::num::ToPrimitive;
+use cstate::{CpuState, Reg8, Reg16, SegReg};
+use datatypes::{Byte, Word};
+use operand::{
+    Operand,
+    operand_value8,
+    operand_set8,
+    operand_value16,
+    operand_set16,
+};
+
+
+/// TEST1, CLR1, SET1 and NOT1 (`op' 0-3) on bit `bit' of `dest'. The bit
+/// number is taken modulo the operand size. Only TEST1 changes flags: ZF
+/// is set if the bit is clear, and CF and OF are cleared.
+pub fn bit_op(cs: &mut CpuState, op: u8, dest: Operand, bit: Byte, byte: bool) {
+    let (val, bit) = if byte {
+        (operand_value8(cs, &dest).to_u16().unwrap(), bit & 0x07)
+    } else {
+        (operand_value16(cs, &dest), bit & 0x0F)
+    };
+    let mask: Word = 1 << (bit as uint);
+
+    let result = match op {
+        0 => {
+            let mut flags = cs.get_flags();
+            flags.zero = val & mask == 0;
+            flags.carry = false;
+            flags.overflow = false;
+            cs.set_flags(flags);
+            return;
+        },
+        1 => val & !mask,
+        2 => val | mask,
+        3 => val ^ mask,
+        _ => panic!("Invalid bit operation"),
+    };
+
+    if byte {
+        operand_set8(cs, &dest, result as Byte);
+    } else {
+        operand_set16(cs, &dest, result);
+    }
+}
+
+/// Add or subtract two BCD digits with a carry or borrow in, returning
+/// the result digit and the carry or borrow out
+fn bcd_digit(left: Byte, right: Byte, carry: Byte, subtract: bool) -> (Byte, Byte) {
+    if subtract {
+        if left >= right + carry {
+            (left - right - carry, 0)
+        } else {
+            (left + 10 - right - carry, 1)
+        }
+    } else {
+        let sum = left + right + carry;
+        if sum > 9 {
+            (sum - 10, 1)
+        } else {
+            (sum, 0)
+        }
+    }
+}
+
+/// Packed BCD string arithmetic on CL digits, least significant byte
+/// first: ES:DI op DS:SI, where DS can be overridden. ZF is set if the
+/// result is zero and CF holds the final carry or borrow.
+fn bcd_string(cs: &mut CpuState, subtract: bool, store: bool) {
+    let bytes = (cs.getreg8(&Reg8::CL).to_u16().unwrap() + 1) / 2;
+    let src_seg = cs.segment(SegReg::DS);
+    let si = cs.getreg16(&Reg16::SI);
+    let di = cs.getreg16(&Reg16::DI);
+
+    let mut carry: Byte = 0;
+    let mut zero = true;
+    for i in range(0, bytes) {
+        let left = cs.getmem(&SegReg::ES, di + i);
+        let right = cs.getmem(&src_seg, si + i);
+        let (low, c) = bcd_digit(left & 0x0F, right & 0x0F, carry, subtract);
+        let (high, c) = bcd_digit(left >> 4, right >> 4, c, subtract);
+        carry = c;
+
+        let result = (high << 4) | low;
+        zero = zero && result == 0;
+        if store {
+            cs.setmem(&SegReg::ES, di + i, result);
+        }
+    }
+
+    let mut flags = cs.get_flags();
+    flags.carry = carry == 1;
+    flags.zero = zero;
+    cs.set_flags(flags);
+}
+
+pub fn add4s(cs: &mut CpuState) {
+    bcd_string(cs, false, true);
+}
+
+pub fn sub4s(cs: &mut CpuState) {
+    bcd_string(cs, true, true);
+}
+
+/// CMP4S: SUB4S without storing the result
+pub fn cmp4s(cs: &mut CpuState) {
+    bcd_string(cs, true, false);
+}
+
+/// ROL4: rotate the three nibbles formed by `dest' and the low half of AL
+/// left by one nibble. The high half of AL is unchanged.
+pub fn rol4(cs: &mut CpuState, dest: Operand) {
+    let val = operand_value8(cs, &dest);
+    let al = cs.getreg8(&Reg8::AL);
+    operand_set8(cs, &dest, (val << 4) | (al & 0x0F));
+    cs.setreg8(&Reg8::AL, (al & 0xF0) | (val >> 4));
+}
+
+/// ROR4: the reverse of ROL4
+pub fn ror4(cs: &mut CpuState, dest: Operand) {
+    let val = operand_value8(cs, &dest);
+    let al = cs.getreg8(&Reg8::AL);
+    operand_set8(cs, &dest, (al << 4) | (val >> 4));
+    cs.setreg8(&Reg8::AL, (al & 0xF0) | (val & 0x0F));
+}
+
+/// Read the 32 bits starting at `seg':`addr', which hold any bit field
+/// beginning in the first word
+fn field_window(cs: &CpuState, seg: SegReg, addr: Word) -> u32 {
+    let low = cs.getmem16(&seg, addr).to_u32().unwrap();
+    let high = cs.getmem16(&seg, addr + 2).to_u32().unwrap();
+    (high << 16) | low
+}
+
+/// Move the bit offset in `offset' past a field ending at bit `end',
+/// stepping `reg' on to the next word if the offset wrapped
+fn advance_field(cs: &mut CpuState, offset: &Operand, reg: Reg16, end: uint) {
+    operand_set8(cs, offset, (end & 0x0F) as Byte);
+    if end > 15 {
+        let val = cs.getreg16(&reg);
+        cs.setreg16(&reg, val + 2);
+    }
+}
+
+/// INS: store the low `length' + 1 bits of AX in the bit field at ES:DI,
+/// starting at the bit offset in `offset' (0-15). The field may run into
+/// the following word.
+pub fn insert_bits(cs: &mut CpuState, offset: Operand, length: Byte) {
+    let start = (operand_value8(cs, &offset) & 0x0F) as uint;
+    let bits = (length & 0x0F) as uint + 1;
+    let di = cs.getreg16(&Reg16::DI);
+
+    let mask: u32 = ((1 << bits) - 1) << start;
+    let field = cs.getreg16(&Reg16::AX).to_u32().unwrap() << start;
+    let window = (field_window(cs, SegReg::ES, di) & !mask) | (field & mask);
+    cs.setmem16(&SegReg::ES, di, window as Word);
+    cs.setmem16(&SegReg::ES, di + 2, (window >> 16) as Word);
+
+    advance_field(cs, &offset, Reg16::DI, start + bits);
+}
+
+/// EXT: load AX with the `length' + 1 bit field at DS:SI, starting at the
+/// bit offset in `offset'. DS can be overridden.
+pub fn extract_bits(cs: &mut CpuState, offset: Operand, length: Byte) {
+    let start = (operand_value8(cs, &offset) & 0x0F) as uint;
+    let bits = (length & 0x0F) as uint + 1;
+    let seg = cs.segment(SegReg::DS);
+    let si = cs.getreg16(&Reg16::SI);
+
+    let field = (field_window(cs, seg, si) >> start) & ((1 << bits) - 1);
+    cs.setreg16(&Reg16::AX, field as Word);
+
+    advance_field(cs, &offset, Reg16::SI, start + bits);
+}
+
+/// BRKEM: call the 8080 program whose address is in interrupt vector
+/// `vector', in 8080 emulation mode. RETEM returns from it.
+pub fn brkem(cs: &mut CpuState, vector: Byte) {
+    cs.interrupt(vector);
+    cs.set_emulation_mode(true);
+}
+
+
+#[cfg(test)]
+mod test {
+    use cstate::{CpuState, Reg8, Reg16, SegReg};
+    use operand::Operand;
+    use super::{add4s, sub4s, cmp4s, rol4, ror4, insert_bits, extract_bits};
+
+    /// Store a packed BCD string at `seg':`addr', least significant byte
+    /// first
+    fn set_bcd(cs: &mut CpuState, seg: SegReg, addr: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            cs.setmem(&seg, addr + i as u16, *byte);
+        }
+    }
+
+    /// A CPU with four-digit BCD strings at ES:DI = 0:0x200 and
+    /// DS:SI = 0:0x300
+    fn bcd_cpu(dest: &[u8], src: &[u8]) -> CpuState {
+        let mut cs = CpuState::new();
+        cs.setreg8(&Reg8::CL, 4);
+        cs.setreg16(&Reg16::DI, 0x200);
+        cs.setreg16(&Reg16::SI, 0x300);
+        set_bcd(&mut cs, SegReg::ES, 0x200, dest);
+        set_bcd(&mut cs, SegReg::DS, 0x300, src);
+        cs
+    }
+
+    #[test]
+    fn add4s_adds_strings() {
+        // 1234 + 5678 = 6912
+        let mut cs = bcd_cpu(&[0x34, 0x12], &[0x78, 0x56]);
+        add4s(&mut cs);
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x200), 0x6912);
+        assert!(!cs.carry());
+        assert!(!cs.zero());
+
+        // 9999 + 0001 = 0000, carry 1
+        let mut cs = bcd_cpu(&[0x99, 0x99], &[0x01, 0x00]);
+        add4s(&mut cs);
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x200), 0x0000);
+        assert!(cs.carry());
+        assert!(cs.zero());
+    }
+
+    #[test]
+    fn sub4s_subtracts_strings() {
+        // 5678 - 1234 = 4444
+        let mut cs = bcd_cpu(&[0x78, 0x56], &[0x34, 0x12]);
+        sub4s(&mut cs);
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x200), 0x4444);
+        assert!(!cs.carry());
+
+        // 1234 - 5678 = 5556, borrow 1
+        let mut cs = bcd_cpu(&[0x34, 0x12], &[0x78, 0x56]);
+        sub4s(&mut cs);
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x200), 0x5556);
+        assert!(cs.carry());
+        assert!(!cs.zero());
+
+        // CMP4S sets the same flags without storing
+        let mut cs = bcd_cpu(&[0x34, 0x12], &[0x34, 0x12]);
+        cmp4s(&mut cs);
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x200), 0x1234);
+        assert!(!cs.carry());
+        assert!(cs.zero());
+    }
+
+    #[test]
+    fn nibble_rotates() {
+        // ROL4: the low nibble of AL moves into the operand, whose high
+        // nibble moves into AL. The high nibble of AL is unchanged.
+        let mut cs = CpuState::new();
+        cs.setreg8(&Reg8::AL, 0x8A);
+        cs.setmem(&SegReg::DS, 0x400, 0x35);
+        rol4(&mut cs, Operand::MemoryAddress(SegReg::DS, 0x400));
+        assert_eq!(cs.getmem(&SegReg::DS, 0x400), 0x5A);
+        assert_eq!(cs.getreg8(&Reg8::AL), 0x83);
+
+        let mut cs = CpuState::new();
+        cs.setreg8(&Reg8::AL, 0x8A);
+        cs.setreg8(&Reg8::BL, 0x35);
+        ror4(&mut cs, Operand::Reg8(Reg8::BL));
+        assert_eq!(cs.getreg8(&Reg8::BL), 0xA3);
+        assert_eq!(cs.getreg8(&Reg8::AL), 0x85);
+    }
+
+    #[test]
+    fn bit_field_across_words() {
+        // An 8-bit field at bit offset 12 spans two words
+        let mut cs = CpuState::new();
+        cs.setreg16(&Reg16::DI, 0x500);
+        cs.setmem16(&SegReg::ES, 0x500, 0x0FFF);
+        cs.setmem16(&SegReg::ES, 0x502, 0xFFF0);
+        cs.setreg16(&Reg16::AX, 0x12AB);
+        cs.setreg8(&Reg8::CL, 12);
+        insert_bits(&mut cs, Operand::Reg8(Reg8::CL), 7);
+
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x500), 0xBFFF);
+        assert_eq!(cs.getmem16(&SegReg::ES, 0x502), 0xFFFA);
+        assert_eq!(cs.getreg8(&Reg8::CL), 4);
+        assert_eq!(cs.getreg16(&Reg16::DI), 0x502);
+
+        cs.setreg16(&Reg16::SI, 0x500);
+        cs.setreg8(&Reg8::CH, 12);
+        extract_bits(&mut cs, Operand::Reg8(Reg8::CH), 7);
+
+        assert_eq!(cs.getreg16(&Reg16::AX), 0x00AB);
+        assert_eq!(cs.getreg8(&Reg8::CH), 4);
+        assert_eq!(cs.getreg16(&Reg16::SI), 0x502);
+    }
+
+    #[test]
+    fn bit_field_within_word() {
+        // A field ending below bit 16 leaves the pointer alone
+        let mut cs = CpuState::new();
+        cs.setreg16(&Reg16::SI, 0x600);
+        cs.setmem16(&SegReg::DS, 0x600, 0x0F0F);
+        cs.setreg8(&Reg8::DL, 4);
+        extract_bits(&mut cs, Operand::Reg8(Reg8::DL), 7);
+
+        assert_eq!(cs.getreg16(&Reg16::AX), 0x00F0);
+        assert_eq!(cs.getreg8(&Reg8::DL), 12);
+        assert_eq!(cs.getreg16(&Reg16::SI), 0x600);
+    }
+}
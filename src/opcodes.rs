@@ -1,103 +1,363 @@
-use cstate::{CpuState, Reg8, Reg16};
+use std::num::ToPrimitive;
+use byteutils::sign_extend8;
+use cstate::{CpuState, CpuModel, Reg8, Reg16, SegReg, RepPrefix, Prefix, DecodeMode};
 use datatypes::Byte;
+use fpu;
+use i8080;
 use modrm;
-use operation::{op8, op16, op8_dry, op16_dry};
-use operand::Operand;
+use necops;
+use operation::{op8, op16, op8_dry, op16_dry, Transform8, Transform16};
+use operand::{
+    Operand,
+    operand_value8,
+    operand_value16,
+    operand_set16,
+    operand_address,
+    operand_far_pointer,
+};
 use specialops;
+use stringops;
 use tf;
 
 
 type F = fn(&mut CpuState, u8);
 
+/// Consume any prefix bytes starting with `opcode', recording them in
+/// `cs', and return the first byte that is not a prefix.
+fn read_prefixes(cs: &mut CpuState, opcode: Byte) -> Byte {
+    cs.begin_instruction();
+
+    let mut opcode = opcode;
+    loop {
+        let prefix = match opcode {
+            0x26 => Prefix::Segment(SegReg::ES),
+            0x2E => Prefix::Segment(SegReg::CS),
+            0x36 => Prefix::Segment(SegReg::SS),
+            0x3E => Prefix::Segment(SegReg::DS),
+            0x64 if cs.model() == CpuModel::V20 => Prefix::Rep(RepPrefix::RepNC),
+            0x65 if cs.model() == CpuModel::V20 => Prefix::Rep(RepPrefix::RepC),
+            0xF0 => Prefix::Lock,
+            0xF1 if cs.model() == CpuModel::I8086 &&
+                    cs.decode_mode() == DecodeMode::Accurate => Prefix::Lock,
+            0xF2 => Prefix::Rep(RepPrefix::RepNE),
+            0xF3 => Prefix::Rep(RepPrefix::RepE),
+            _ => return opcode,
+        };
+        cs.add_prefix(prefix);
+        opcode = cs.read();
+    }
+}
+
+/// Execute one instruction, unless the CPU is halted, and then recognise
+/// any pending interrupts. A V20 in emulation mode runs 8080 code instead.
+pub fn step(cs: &mut CpuState) {
+    if !cs.halted() {
+        if cs.emulation_mode() {
+            i8080::step(cs);
+        } else {
+            let opcode = cs.read();
+            do_opcode(cs, opcode);
+        }
+    }
+
+    // An invalid opcode stops the CPU at the instruction
+    if cs.invalid_opcode().is_none() {
+        cs.service_interrupts();
+    }
+}
+
+/// Report an opcode the CPU cannot execute. The 8086 and V20 have no way
+/// to do this, so emulation stops at the instruction. The 80186 raises a
+/// type 6 interrupt with the saved IP pointing at the invalid instruction.
+fn invalid_opcode(cs: &mut CpuState, opcode: Byte) {
+    match cs.model() {
+        CpuModel::I80186 => {
+            cs.restart_instruction();
+            cs.interrupt(6);
+        },
+        CpuModel::I8086 | CpuModel::V20 => cs.stop_invalid_opcode(opcode),
+    }
+}
+
+/// Decide whether an undocumented encoding of `opcode' may run. In
+/// accurate mode it behaves as on real 8086 silicon; in strict mode it is
+/// reported as invalid and false is returned.
+fn allow_undocumented(cs: &mut CpuState, opcode: Byte) -> bool {
+    match cs.decode_mode() {
+        DecodeMode::Accurate => true,
+        DecodeMode::Strict => {
+            invalid_opcode(cs, opcode);
+            false
+        },
+    }
+}
+
+/// Like `allow_undocumented', for 8086 aliases that the 80186 replaced
+/// with the invalid opcode trap
+fn allow_8086_alias(cs: &mut CpuState, opcode: Byte) -> bool {
+    match cs.model() {
+        CpuModel::I8086 => allow_undocumented(cs, opcode),
+        _ => {
+            invalid_opcode(cs, opcode);
+            false
+        },
+    }
+}
+
+/// Opcodes that are undocumented aliases on `model'. Undocumented ModR/M
+/// encodings of documented opcodes are checked by their handlers.
+fn undocumented_opcode(model: CpuModel, opcode: Byte) -> bool {
+    match (model, opcode) {
+        (_, 0xD6) |
+        (CpuModel::I8086, 0x0F) |
+        (CpuModel::I8086, 0x60...0x6F) |
+        (CpuModel::I8086, 0xC0) |
+        (CpuModel::I8086, 0xC1) |
+        (CpuModel::I8086, 0xC8) |
+        (CpuModel::I8086, 0xC9) |
+        (CpuModel::I8086, 0xF1) => true,
+        _ => false,
+    }
+}
+
+/// Opcodes left undefined on `model'. The 80186 raises the invalid opcode
+/// trap for these rather than running the 8086's aliases. The V20 uses 0F
+/// as the prefix for its own instructions, 64 and 65 as the REPNC and
+/// REPC prefixes, and 66 and 67 as coprocessor escapes; 63 and F1 are
+/// undefined, and stop emulation as on the 8086 in strict mode.
+fn undefined_opcode(model: CpuModel, opcode: Byte) -> bool {
+    match (model, opcode) {
+        (CpuModel::I80186, 0x0F) |
+        (CpuModel::I80186, 0x63...0x67) |
+        (CpuModel::I80186, 0xF1) |
+        (CpuModel::V20, 0x63) |
+        (CpuModel::V20, 0xF1) => true,
+        _ => false,
+    }
+}
+
+/// Opcodes the 80186, and with it the V20, added in place of 8086 aliases
+fn extended_opcode(model: CpuModel, opcode: Byte) -> bool {
+    match (model, opcode) {
+        (CpuModel::I8086, _) => false,
+        (_, 0x60...0x62) |
+        (_, 0x68...0x6F) |
+        (_, 0xC0) |
+        (_, 0xC1) |
+        (_, 0xC8) |
+        (_, 0xC9) => true,
+        _ => false,
+    }
+}
+
 pub fn do_opcode(cs: &mut CpuState, opcode: Byte) {
+    let opcode = read_prefixes(cs, opcode);
+    let model = cs.model();
+    if undefined_opcode(model, opcode) {
+        return invalid_opcode(cs, opcode);
+    }
+    if undocumented_opcode(model, opcode) && !allow_undocumented(cs, opcode) {
+        return;
+    }
+
     // TODO - Don't duplicate opcode definitions here and in their do_* method
 
     let func: F = match opcode {
-        0x04 |
-        0x3C |
-        0x72 |
-        0x74...0x79 |
-        0xB0 |
-        0xB1 |
-        0xB2 |
-        0xB3 |
-        0xB4 |
-        0xB5 |
-        0xB6 |
-        0xB7 |
+        _ if extended_opcode(model, opcode) => ext186_opcode,
+        0x0F if model == CpuModel::V20 => nec_opcode,
+        0x66 | 0x67 if model == CpuModel::V20 => fpo2_opcode,
+
+        0x00...0x05 |
+        0x08...0x0D |
+        0x10...0x15 |
+        0x18...0x1D |
+        0x20...0x25 |
+        0x28...0x2D |
+        0x30...0x35 |
+        0x38...0x3D => alu_opcode,
+
+        0x60...0x7F |
+        0xA8 |
+        0xB0...0xB7 |
+        0xCD |
+        0xD4 |
+        0xD5 |
+        0xE0...0xE3 |
         0xEB => b_opcode_i,
 
-        0x05 |
-        0x2D |
-        0x3D |
-        0xB8 |
-        0xB9 |
-        0xBA...0xBF |
+        0xA9 |
+        0xB8...0xBF |
+        0xC0 |
+        0xC2 |
+        0xC8 |
+        0xCA |
         0xE8 |
         0xE9 => w_opcode_i,
 
+        0x84 |
         0x86 |
         0x88 |
-        0x8A |
-        0x38 => b_opcode_m,
-
-        0x01 |
-        0x09 |
-        0x19 |
-        0x20 |
-        0x29 |
-        0x31 |
-        0x39 |
+        0x8A => b_opcode_m,
+
+        0x85 |
+        0x87 |
         0x89 |
-        0x8B => w_opcode_m,
+        0x8B |
+        0x8D |
+        0xC4 |
+        0xC5 => w_opcode_m,
+
+        0xA0...0xA3 => moffs_opcode,
+
+        0xA4...0xA7 |
+        0xAA...0xAF => stringops::string_op,
+
+        0x9A |
+        0xEA => far_opcode_i,
+
+        0x8C |
+        0x8E => sreg_opcode_m,
+
+        0x8F => pop_group,
+
+        0xE4...0xE7 |
+        0xEC...0xEF => io_opcode,
 
         0xC6 => b_opcode_mi,
 
         0xC7 => w_opcode_mi,
 
-        0x40...0x4C |
+        0x06 |
+        0x07 |
+        0x0E |
+        0x0F |
+        0x16 |
+        0x17 |
+        0x1E |
+        0x1F |
+        0x27 |
+        0x2F |
+        0x37 |
+        0x3F |
+        0x40...0x4F |
         0x50...0x5F |
-        0x90 |
-        0x92 |
+        0x90...0x97 |
+        0x98 |
+        0x99 |
+        0x9B |
+        0x9C...0x9F |
+        0xC1 |
         0xC3 |
-        0xF9 => opcode_noargs,
+        0xC9 |
+        0xCB |
+        0xCC |
+        0xCE |
+        0xCF |
+        0xD6 |
+        0xD7 |
+        0xF4 |
+        0xF5 |
+        0xF8...0xFD => opcode_noargs,
+
+        0x80 |
+        0x82 => b_group_i,
+
+        0x81 |
+        0x83 => w_group_i,
 
-        0x80 => b_group_i,
-        0x81 => w_group_i,
+        0xD0...0xD3 => shift_group,
+
+        0xF6 => b_group3,
+        0xF7 => w_group3,
+
+        0xD8...0xDF => fpu::esc_opcode,
 
         0xFE => b_group_noargs,
+        0xFF => w_group_noargs,
 
-        _ => {
-            panic!("Unrecognized opcode: 0x{:X}", opcode);
-        }
+        _ => invalid_opcode,
     };
 
     func(cs, opcode);
 }
 
+/// The eight ALU operations, indexed by bits 3-5 of opcodes 0x00-0x3F
+/// and by the ModR/M reg field of the immediate group 0x80-0x83. The
+/// bool is false for CMP, which only sets flags.
+fn alu_op(index: u8) -> (Transform8, Transform16, bool) {
+    match index {
+        0b000 => (tf::add8 as Transform8, tf::add16 as Transform16, true),
+        0b001 => (tf::or8 as Transform8, tf::or16 as Transform16, true),
+        0b010 => (tf::adc8 as Transform8, tf::adc16 as Transform16, true),
+        0b011 => (tf::sbb8 as Transform8, tf::sbb16 as Transform16, true),
+        0b100 => (tf::and8 as Transform8, tf::and16 as Transform16, true),
+        0b101 => (tf::sub8 as Transform8, tf::sub16 as Transform16, true),
+        0b110 => (tf::xor8 as Transform8, tf::xor16 as Transform16, true),
+        0b111 => (tf::sub8 as Transform8, tf::sub16 as Transform16, false),
+        _ => panic!("Invalid ALU operation"),
+    }
+}
+
+fn alu8(cs: &mut CpuState, dest: Operand, src: Operand, tf: Transform8, store: bool) {
+    if store {
+        op8(cs, dest, src, tf);
+    } else {
+        op8_dry(cs, dest, src, tf);
+    }
+}
+
+fn alu16(cs: &mut CpuState, dest: Operand, src: Operand, tf: Transform16, store: bool) {
+    if store {
+        op16(cs, dest, src, tf);
+    } else {
+        op16_dry(cs, dest, src, tf);
+    }
+}
+
+/// Opcodes 0x00-0x3F with a low octal digit of 0-5. Bits 3-5 select the
+/// operation and bits 0-2 select one of six encodings:
+/// Eb,Gb / Ev,Gv / Gb,Eb / Gv,Ev / AL,Ib / AX,Iv
+fn alu_opcode(cs: &mut CpuState, opcode: Byte) {
+    let (tf8, tf16, store) = alu_op((opcode >> 3) & 0b111);
+
+    match opcode & 0b111 {
+        0b000 => {
+            let (_, eff, reg) = modrm::read_modrm(cs, true);
+            alu8(cs, eff, reg, tf8, store);
+        },
+        0b001 => {
+            let (_, eff, reg) = modrm::read_modrm(cs, false);
+            alu16(cs, eff, reg, tf16, store);
+        },
+        0b010 => {
+            let (_, eff, reg) = modrm::read_modrm(cs, true);
+            alu8(cs, reg, eff, tf8, store);
+        },
+        0b011 => {
+            let (_, eff, reg) = modrm::read_modrm(cs, false);
+            alu16(cs, reg, eff, tf16, store);
+        },
+        0b100 => {
+            let immediate = Operand::RawByte(cs.read());
+            alu8(cs, Operand::Reg8(Reg8::AL), immediate, tf8, store);
+        },
+        0b101 => {
+            let immediate = Operand::RawWord(cs.read16());
+            alu16(cs, Operand::Reg16(Reg16::AX), immediate, tf16, store);
+        },
+        _ => panic!("Invalid opcode"),
+    };
+}
+
 fn b_opcode_i(cs: &mut CpuState, opcode: Byte) {
     let immediate_raw = cs.read();
     let immediate = Operand::RawByte(immediate_raw);
 
     match opcode {
-        0x04 => op8(cs, Operand::Reg8(Reg8::AL), immediate, tf::add8),
-
-        0x3C => op8_dry(cs, Operand::Reg8(Reg8::AL), immediate, tf::sub8),
-
-        0x72 => specialops::jmp_flag(cs, CpuState::carry, false, immediate_raw),
-        0x74 => specialops::jmp_flag(cs, CpuState::zero, false, immediate_raw),
-        0x75 => specialops::jmp_flag(cs, CpuState::zero, true, immediate_raw),
-        0x76 => specialops::jmp_flags(cs,
-                                      CpuState::carry,
-                                      CpuState::zero,
-                                      false,
-                                      immediate_raw),
-        0x77 => specialops::jmp_flags(cs,
-                                      CpuState::carry,
-                                      CpuState::zero,
-                                      true,
-                                      immediate_raw),
-        0x79 => specialops::jmp_flag(cs, CpuState::sign, true, immediate_raw),
+        // 0x60-0x6F are undocumented aliases of 0x70-0x7F
+        0x60...0x7F => specialops::jcc(cs, opcode & 0x0F, immediate_raw),
+
+        0xA8 => op8_dry(cs, Operand::Reg8(Reg8::AL), immediate, tf::and8),
 
         0xB0 => op8(cs, Operand::Reg8(Reg8::AL), immediate, tf::noop8),
         0xB1 => op8(cs, Operand::Reg8(Reg8::CL), immediate, tf::noop8),
@@ -108,6 +368,16 @@ fn b_opcode_i(cs: &mut CpuState, opcode: Byte) {
         0xB6 => op8(cs, Operand::Reg8(Reg8::DH), immediate, tf::noop8),
         0xB7 => op8(cs, Operand::Reg8(Reg8::BH), immediate, tf::noop8),
 
+        0xCD => cs.interrupt(immediate_raw),
+
+        0xD4 => specialops::aam(cs, immediate_raw),
+        0xD5 => specialops::aad(cs, immediate_raw),
+
+        0xE0 => specialops::loop_cx(cs, Some(false), immediate_raw),
+        0xE1 => specialops::loop_cx(cs, Some(true), immediate_raw),
+        0xE2 => specialops::loop_cx(cs, None, immediate_raw),
+        0xE3 => specialops::jcxz(cs, immediate_raw),
+
         0xEB => specialops::jmp8(cs, immediate_raw),
 
         _ => panic!("Invalid opcode"),
@@ -119,19 +389,21 @@ fn w_opcode_i(cs: &mut CpuState, opcode: Byte) {
     let immediate = Operand::RawWord(immediate_raw);
 
     match opcode {
-        0x05 => op16(cs, Operand::Reg16(Reg16::AX), immediate, tf::add16),
-
-        0x2D => op16(cs, Operand::Reg16(Reg16::AX), immediate, tf::sub16),
-        0x3D => op16_dry(cs, Operand::Reg16(Reg16::AX), immediate, tf::sub16),
+        0xA9 => op16_dry(cs, Operand::Reg16(Reg16::AX), immediate, tf::and16),
 
         0xB8 => op16(cs, Operand::Reg16(Reg16::AX), immediate, tf::noop16),
         0xB9 => op16(cs, Operand::Reg16(Reg16::CX), immediate, tf::noop16),
         0xBA => op16(cs, Operand::Reg16(Reg16::DX), immediate, tf::noop16),
         0xBB => op16(cs, Operand::Reg16(Reg16::BX), immediate, tf::noop16),
         0xBC => op16(cs, Operand::Reg16(Reg16::SP), immediate, tf::noop16),
+        0xBD => op16(cs, Operand::Reg16(Reg16::BP), immediate, tf::noop16),
         0xBE => op16(cs, Operand::Reg16(Reg16::SI), immediate, tf::noop16),
         0xBF => op16(cs, Operand::Reg16(Reg16::DI), immediate, tf::noop16),
 
+        // C0 and C8 are undocumented aliases of C2 and CA
+        0xC0 | 0xC2 => specialops::ret_release(cs, immediate_raw),
+        0xC8 | 0xCA => specialops::retf_release(cs, immediate_raw),
+
         0xE8 => specialops::call(cs, immediate_raw),
         0xE9 => specialops::jmp16(cs, immediate_raw),
 
@@ -139,15 +411,29 @@ fn w_opcode_i(cs: &mut CpuState, opcode: Byte) {
     };
 }
 
+/// Direct far CALL and JMP, with a segment:offset immediate stored as the
+/// offset followed by the segment.
+fn far_opcode_i(cs: &mut CpuState, opcode: Byte) {
+    let offset = cs.read16();
+    let segment = cs.read16();
+
+    match opcode {
+        0x9A => specialops::call_far(cs, segment, offset),
+        0xEA => specialops::jmp_far(cs, segment, offset),
+
+        _ => panic!("Invalid opcode"),
+    };
+}
+
 fn b_opcode_m(cs: &mut CpuState, opcode: Byte) {
     let (_, eff, reg) = modrm::read_modrm(cs, true);
 
     match opcode {
+        0x84 => op8_dry(cs, eff, reg, tf::and8),
         0x86 => specialops::xchg8(cs, eff, reg),
 
         0x88 => op8(cs, eff, reg, tf::noop8),
         0x8A => op8(cs, reg, eff, tf::noop8),
-        0x38 => op8_dry(cs, eff, reg, tf::sub8),
 
         _ => panic!("Invalid opcode"),
     };
@@ -157,15 +443,115 @@ fn w_opcode_m(cs: &mut CpuState, opcode: Byte) {
     let (_, eff, reg) = modrm::read_modrm(cs, false);
 
     match opcode {
-        0x01 => op16(cs, eff, reg, tf::add16),
-        0x09 => op16(cs, eff, reg, tf::or16),
-        0x19 => op16(cs, eff, reg, tf::sbb16),
-        0x20 => op16(cs, eff, reg, tf::and16),
-        0x29 => op16(cs, eff, reg, tf::sub16),
-        0x31 => op16(cs, eff, reg, tf::xor16),
-        0x39 => op16_dry(cs, eff, reg, tf::sub16),
+        0x85 => op16_dry(cs, eff, reg, tf::and16),
+        0x87 => specialops::xchg16(cs, eff, reg),
         0x89 => op16(cs, eff, reg, tf::noop16),
         0x8B => op16(cs, reg, eff, tf::noop16),
+        // LEA, LES and LDS: see `memory_operand' for a register operand
+        0x8D | 0xC4 | 0xC5 => {
+            let mem = match memory_operand(cs, opcode, eff) {
+                Some(mem) => mem,
+                None => return,
+            };
+            match opcode {
+                0x8D => {
+                    let addr = operand_address(&mem);
+                    operand_set16(cs, &reg, addr);
+                },
+                _ => {
+                    let (offset, segment) = operand_far_pointer(cs, &mem);
+                    operand_set16(cs, &reg, offset);
+                    let seg = if opcode == 0xC4 { SegReg::ES } else { SegReg::DS };
+                    cs.setseg(&seg, segment);
+                },
+            }
+        },
+
+        _ => panic!("Invalid opcode"),
+    };
+}
+
+/// MOV Ew,Sreg (8C) and MOV Sreg,Ew (8E). The 8086 allows loading CS
+/// this way, which acts as a jump, and only decodes the low two bits of
+/// the reg field; later models treat reg values 4-7 as invalid.
+fn sreg_opcode_m(cs: &mut CpuState, opcode: Byte) {
+    let (rb, eff, _) = modrm::read_modrm(cs, false);
+    let seg = match modrm::segment_register(rb) {
+        Some(seg) => seg,
+        None => {
+            if !allow_8086_alias(cs, opcode) {
+                return;
+            }
+            modrm::segment_register(rb & 0b011).unwrap()
+        },
+    };
+
+    match opcode {
+        0x8C => op16(cs, eff, Operand::SegReg(seg), tf::noop16),
+        0x8E => specialops::mov_seg(cs, seg, eff),
+
+        _ => panic!("Invalid opcode"),
+    };
+}
+
+/// 8F: POP Ev
+fn pop_group(cs: &mut CpuState, opcode: Byte) {
+    if opcode != 0x8F {
+        panic!("Invalid opcode");
+    }
+
+    let (rb, eff, _) = modrm::read_modrm(cs, false);
+
+    match rb {
+        0b000 => {
+            let val = cs.pop();
+            operand_set16(cs, &eff, val);
+        },
+        _ => invalid_opcode(cs, opcode),
+    }
+}
+
+/// MOV between AL/AX and a direct memory offset (A0-A3). The offset is
+/// in DS unless overridden.
+fn moffs_opcode(cs: &mut CpuState, opcode: Byte) {
+    let addr = cs.read16();
+    let mem = Operand::MemoryAddress(cs.segment(SegReg::DS), addr);
+
+    match opcode {
+        0xA0 => op8(cs, Operand::Reg8(Reg8::AL), mem, tf::noop8),
+        0xA1 => op16(cs, Operand::Reg16(Reg16::AX), mem, tf::noop16),
+        0xA2 => op8(cs, mem, Operand::Reg8(Reg8::AL), tf::noop8),
+        0xA3 => op16(cs, mem, Operand::Reg16(Reg16::AX), tf::noop16),
+
+        _ => panic!("Invalid opcode"),
+    };
+}
+
+/// IN and OUT, with the port given as an immediate byte (E4-E7) or in
+/// DX (EC-EF)
+fn io_opcode(cs: &mut CpuState, opcode: Byte) {
+    let port = match opcode {
+        0xE4...0xE7 => cs.read().to_u16().unwrap(),
+        _ => cs.getreg16(&Reg16::DX),
+    };
+
+    match opcode {
+        0xE4 | 0xEC => {
+            let val = cs.port_in8(port);
+            cs.setreg8(&Reg8::AL, val);
+        },
+        0xE5 | 0xED => {
+            let val = cs.port_in16(port);
+            cs.setreg16(&Reg16::AX, val);
+        },
+        0xE6 | 0xEE => {
+            let val = cs.getreg8(&Reg8::AL);
+            cs.port_out8(port, val);
+        },
+        0xE7 | 0xEF => {
+            let val = cs.getreg16(&Reg16::AX);
+            cs.port_out16(port, val);
+        },
 
         _ => panic!("Invalid opcode"),
     };
@@ -197,8 +583,10 @@ fn w_opcode_mi(cs: &mut CpuState, opcode: Byte) {
     };
 }
 
+/// Immediate group 0x80/0x82: ALU operation on Eb,Ib. 0x82 is an alias
+/// of 0x80.
 fn b_group_i(cs: &mut CpuState, opcode: Byte) {
-    if opcode != 0x80 {
+    if opcode != 0x80 && opcode != 0x82 {
         panic!("Invalid opcode");
     }
 
@@ -207,29 +595,134 @@ fn b_group_i(cs: &mut CpuState, opcode: Byte) {
     let immediate = cs.read();
     let immediate = Operand::RawByte(immediate);
 
+    let (tf8, _, store) = alu_op(rb);
+    alu8(cs, eff, immediate, tf8, store);
+}
+
+/// Immediate group 0x81/0x83: ALU operation on Ev,Iv, or on Ev with a
+/// sign-extended byte immediate for 0x83.
+fn w_group_i(cs: &mut CpuState, opcode: Byte) {
+    let (rb, eff, _) = modrm::read_modrm(cs, false);
+
+    let immediate_raw = match opcode {
+        0x81 => cs.read16(),
+        0x83 => sign_extend8(cs.read()),
+        _ => panic!("Invalid opcode"),
+    };
+    let immediate = Operand::RawWord(immediate_raw);
+
+    let (_, tf16, store) = alu_op(rb);
+    alu16(cs, eff, immediate, tf16, store);
+}
+
+/// The eight shift and rotate operations, indexed by the ModR/M reg
+/// field of the D0-D3 group.
+fn shift_op(index: u8) -> (Transform8, Transform16) {
+    match index {
+        0b000 => (tf::rol8 as Transform8, tf::rol16 as Transform16),
+        0b001 => (tf::ror8 as Transform8, tf::ror16 as Transform16),
+        0b010 => (tf::rcl8 as Transform8, tf::rcl16 as Transform16),
+        0b011 => (tf::rcr8 as Transform8, tf::rcr16 as Transform16),
+        0b100 => (tf::shl8 as Transform8, tf::shl16 as Transform16),
+        0b101 => (tf::shr8 as Transform8, tf::shr16 as Transform16),
+        0b110 => (tf::setmo8 as Transform8, tf::setmo16 as Transform16),
+        0b111 => (tf::sar8 as Transform8, tf::sar16 as Transform16),
+        _ => panic!("Invalid shift operation"),
+    }
+}
+
+/// Shift/rotate group D0-D3: Eb,1 / Ev,1 / Eb,CL / Ev,CL
+fn shift_group(cs: &mut CpuState, opcode: Byte) {
+    let byte = opcode & 0b01 == 0;
+    let (rb, eff, _) = modrm::read_modrm(cs, byte);
+    if rb == 0b110 && !allow_undocumented(cs, opcode) {
+        return;
+    }
+
+    let count = match opcode & 0b10 {
+        0 => 1,
+        _ => cs.getreg8(&Reg8::CL),
+    };
+    shift(cs, byte, rb, eff, count);
+}
+
+/// Shift or rotate `eff' by `count'. The 80186 only uses the low five
+/// bits of the count.
+fn shift(cs: &mut CpuState, byte: bool, rb: u8, eff: Operand, count: Byte) {
+    let count = match cs.model() {
+        CpuModel::I8086 => count,
+        _ => count & 0x1F,
+    };
+
+    let (tf8, tf16) = shift_op(rb);
+    if byte {
+        op8(cs, eff, Operand::RawByte(count), tf8);
+    } else {
+        op16(cs, eff, Operand::RawWord(count.to_u16().unwrap()), tf16);
+    }
+}
+
+/// Group 3 F6: TEST/NOT/NEG/MUL/IMUL/DIV/IDIV on Eb. /1 is an
+/// undocumented alias of TEST.
+fn b_group3(cs: &mut CpuState, opcode: Byte) {
+    if opcode != 0xF6 {
+        panic!("Invalid opcode");
+    }
+
+    let (rb, eff, _) = modrm::read_modrm(cs, true);
+
     match rb {
-        0b001 => op8(cs, eff, immediate, tf::or8),
-        0b111 => op8_dry(cs, eff, immediate, tf::sub8),
-        _ => panic!("b_group_i: Not Implemented: 0b{:b}", rb),
+        0b000 | 0b001 => {
+            if rb == 0b001 && !allow_undocumented(cs, opcode) {
+                return;
+            }
+            let immediate = Operand::RawByte(cs.read());
+            op8_dry(cs, eff, immediate, tf::and8);
+        },
+        0b010 => op8(cs, eff, Operand::RawByte(0), tf::not8),
+        0b011 => op8(cs, eff, Operand::RawByte(0), tf::neg8),
+        _ => {
+            let src = operand_value8(cs, &eff);
+            match rb {
+                0b100 => specialops::mul8(cs, src),
+                0b101 => specialops::imul8(cs, src),
+                0b110 => specialops::div8(cs, src),
+                0b111 => specialops::idiv8(cs, src),
+                _ => panic!("Invalid ModR/M byte"),
+            }
+        },
     }
 }
 
-fn w_group_i(cs: &mut CpuState, opcode: Byte) {
-    if opcode != 0x81 {
+/// Group 3 F7: TEST/NOT/NEG/MUL/IMUL/DIV/IDIV on Ev. /1 is an
+/// undocumented alias of TEST.
+fn w_group3(cs: &mut CpuState, opcode: Byte) {
+    if opcode != 0xF7 {
         panic!("Invalid opcode");
     }
 
     let (rb, eff, _) = modrm::read_modrm(cs, false);
 
-    let immediate_raw = cs.read16();
-    let immediate = Operand::RawWord(immediate_raw);
-
     match rb {
-        0b111 => op16_dry(cs, eff, immediate, tf::sub16),
-        0b101 => op16(cs, eff, immediate, tf::sub16),
-        0b010 => op16(cs, eff, immediate, tf::adc16),
-        0b000 => op16(cs, eff, immediate, tf::add16),
-        _ => println!("w_group_i: Not Implemented: 0b{:b}", rb),
+        0b000 | 0b001 => {
+            if rb == 0b001 && !allow_undocumented(cs, opcode) {
+                return;
+            }
+            let immediate = Operand::RawWord(cs.read16());
+            op16_dry(cs, eff, immediate, tf::and16);
+        },
+        0b010 => op16(cs, eff, Operand::RawWord(0), tf::not16),
+        0b011 => op16(cs, eff, Operand::RawWord(0), tf::neg16),
+        _ => {
+            let src = operand_value16(cs, &eff);
+            match rb {
+                0b100 => specialops::mul16(cs, src),
+                0b101 => specialops::imul16(cs, src),
+                0b110 => specialops::div16(cs, src),
+                0b111 => specialops::idiv16(cs, src),
+                _ => panic!("Invalid ModR/M byte"),
+            }
+        },
     }
 }
 
@@ -241,31 +734,242 @@ fn b_group_noargs(cs: &mut CpuState, opcode: Byte) {
     let (rb, eff, _) = modrm::read_modrm(cs, true);
 
     match rb {
-        0b000 => op8(cs, eff, Operand::RawByte(1), tf::add8),
-        0b001 => op8(cs, eff, Operand::RawByte(1), tf::sub8),
-        _ => panic!("b_group_noargs: Invalid reg value"),
+        0b000 => op8(cs, eff, Operand::RawByte(1), tf::inc8),
+        0b001 => op8(cs, eff, Operand::RawByte(1), tf::dec8),
+        _ => {
+            if !allow_8086_alias(cs, opcode) {
+                return;
+            }
+
+            // The 8086 performs the FF operation anyway, but with a byte
+            // operand whose upper half reads as all ones.
+            let val = 0xFF00 | operand_value8(cs, &eff).to_u16().unwrap();
+            match rb {
+                0b010 => specialops::call_near(cs, val),
+                0b011 | 0b101 => far_indirect(cs, opcode, rb, eff),
+                0b100 => cs.setreg16(&Reg16::IP, val),
+                _ => cs.push(val),
+            }
+        },
     }
 }
 
+/// Check that `eff' is the memory operand that `opcode' requires. Given
+/// a register instead, the 8086 uses the effective address it last
+/// calculated; other models report an invalid opcode.
+fn memory_operand(cs: &mut CpuState, opcode: Byte, eff: Operand) -> Option<Operand> {
+    match eff {
+        Operand::MemoryAddress(_, _) => return Some(eff),
+        _ => {},
+    }
+    if !allow_8086_alias(cs, opcode) {
+        return None;
+    }
+    let (seg, addr) = cs.last_address();
+    Some(Operand::MemoryAddress(seg, addr))
+}
+
+/// Indirect far CALL (/3) or JMP (/5) through the pointer at `eff'
+fn far_indirect(cs: &mut CpuState, opcode: Byte, rb: u8, eff: Operand) {
+    let mem = match memory_operand(cs, opcode, eff) {
+        Some(mem) => mem,
+        None => return,
+    };
+    let (offset, segment) = operand_far_pointer(cs, &mem);
+    match rb {
+        0b011 => specialops::call_far(cs, segment, offset),
+        _ => specialops::jmp_far(cs, segment, offset),
+    }
+}
+
+/// Group 5 FF: INC/DEC, indirect near and far CALL and JMP, and PUSH on Ev
+fn w_group_noargs(cs: &mut CpuState, opcode: Byte) {
+    if opcode != 0xFF {
+        panic!("Invalid opcode");
+    }
+
+    let (rb, eff, _) = modrm::read_modrm(cs, false);
+
+    match rb {
+        0b000 => op16(cs, eff, Operand::RawWord(1), tf::inc16),
+        0b001 => op16(cs, eff, Operand::RawWord(1), tf::dec16),
+        0b010 => {
+            let target = operand_value16(cs, &eff);
+            specialops::call_near(cs, target);
+        },
+        0b011 | 0b101 => far_indirect(cs, opcode, rb, eff),
+        0b100 => {
+            let target = operand_value16(cs, &eff);
+            cs.setreg16(&Reg16::IP, target);
+        },
+        0b110 => specialops::push_operand(cs, &eff),
+        // FF /7 is an undocumented alias of PUSH
+        0b111 => {
+            if allow_8086_alias(cs, opcode) {
+                specialops::push_operand(cs, &eff);
+            }
+        },
+        _ => invalid_opcode(cs, opcode),
+    }
+}
+
+/// 80186 instructions in opcodes the 8086 decodes as aliases: PUSHA,
+/// POPA, BOUND, PUSH imm, IMUL imm, INS/OUTS, shifts by an immediate
+/// count, ENTER and LEAVE
+fn ext186_opcode(cs: &mut CpuState, opcode: Byte) {
+    match opcode {
+        0x60 => specialops::pusha(cs),
+        0x61 => specialops::popa(cs),
+        0x62 => {
+            let (_, eff, reg) = modrm::read_modrm(cs, false);
+            match eff {
+                Operand::MemoryAddress(seg, addr) => specialops::bound(cs, reg, seg, addr),
+                _ => invalid_opcode(cs, opcode),
+            }
+        },
+
+        0x68 => {
+            let immediate = cs.read16();
+            cs.push(immediate);
+        },
+        0x6A => {
+            let immediate = sign_extend8(cs.read());
+            cs.push(immediate);
+        },
+
+        0x69 | 0x6B => {
+            let (_, eff, reg) = modrm::read_modrm(cs, false);
+            let src = operand_value16(cs, &eff);
+            let immediate = match opcode {
+                0x69 => cs.read16(),
+                _ => sign_extend8(cs.read()),
+            };
+            specialops::imul_immediate(cs, reg, src, immediate);
+        },
+
+        0x6C...0x6F => stringops::string_op(cs, opcode),
+
+        0xC0 | 0xC1 => {
+            let byte = opcode == 0xC0;
+            let (rb, eff, _) = modrm::read_modrm(cs, byte);
+            if rb == 0b110 && !allow_undocumented(cs, opcode) {
+                return;
+            }
+            let count = cs.read();
+            shift(cs, byte, rb, eff, count);
+        },
+
+        0xC8 => {
+            let size = cs.read16();
+            let level = cs.read();
+            specialops::enter(cs, size, level);
+        },
+        0xC9 => specialops::leave(cs),
+
+        _ => unreachable!(),
+    };
+}
+
+/// NEC V20/V30 instructions behind the 0F prefix: the bit operations
+/// TEST1, CLR1, SET1 and NOT1, packed BCD strings, nibble rotates, bit
+/// field INS/EXT, and BRKEM
+fn nec_opcode(cs: &mut CpuState, _: Byte) {
+    let function = cs.read();
+
+    match function {
+        // Bit number in CL (10-17) or an immediate byte (18-1F)
+        0x10...0x1F => {
+            let byte = function & 1 == 0;
+            let (_, eff, _) = modrm::read_modrm(cs, byte);
+            let bit = match function & 0x08 {
+                0 => cs.getreg8(&Reg8::CL),
+                _ => cs.read(),
+            };
+            necops::bit_op(cs, (function >> 1) & 0b11, eff, bit, byte);
+        },
+
+        0x20 => necops::add4s(cs),
+        0x22 => necops::sub4s(cs),
+        0x26 => necops::cmp4s(cs),
+
+        0x28 | 0x2A => {
+            let (_, eff, _) = modrm::read_modrm(cs, true);
+            match function {
+                0x28 => necops::rol4(cs, eff),
+                _ => necops::ror4(cs, eff),
+            }
+        },
+
+        // The bit offset register is the r/m operand; the field length
+        // is in the reg operand (31, 33) or an immediate byte (39, 3B).
+        0x31 | 0x33 | 0x39 | 0x3B => {
+            let (_, eff, reg) = modrm::read_modrm(cs, true);
+            let length = match function {
+                0x31 | 0x33 => operand_value8(cs, &reg),
+                _ => cs.read(),
+            };
+            match function {
+                0x31 | 0x39 => necops::insert_bits(cs, eff, length),
+                _ => necops::extract_bits(cs, eff, length),
+            }
+        },
+
+        0xFF => {
+            let vector = cs.read();
+            necops::brkem(cs, vector);
+        },
+
+        _ => invalid_opcode(cs, function),
+    };
+}
+
+/// V20 FPO2 escapes (66, 67) to NEC's own numeric coprocessor, which
+/// isn't emulated. As with ESC and no coprocessor, only the ModR/M operand
+/// is decoded.
+fn fpo2_opcode(cs: &mut CpuState, _: Byte) {
+    modrm::read_modrm(cs, false);
+}
+
 fn opcode_noargs(cs: &mut CpuState, opcode: Byte) {
     match opcode {
-        0x40 => op16(cs, Operand::Reg16(Reg16::AX), Operand::RawWord(1), tf::add16),
-        0x41 => op16(cs, Operand::Reg16(Reg16::CX), Operand::RawWord(1), tf::add16),
-        0x42 => op16(cs, Operand::Reg16(Reg16::DX), Operand::RawWord(1), tf::add16),
-        0x43 => op16(cs, Operand::Reg16(Reg16::BX), Operand::RawWord(1), tf::add16),
-        0x47 => op16(cs, Operand::Reg16(Reg16::DI), Operand::RawWord(1), tf::add16),
-
-        0x48 => op16(cs, Operand::Reg16(Reg16::AX), Operand::RawWord(1), tf::sub16),
-        0x49 => op16(cs, Operand::Reg16(Reg16::CX), Operand::RawWord(1), tf::sub16),
-        0x4A => op16(cs, Operand::Reg16(Reg16::DX), Operand::RawWord(1), tf::sub16),
-        0x4B => op16(cs, Operand::Reg16(Reg16::BX), Operand::RawWord(1), tf::sub16),
-        0x4C => op16(cs, Operand::Reg16(Reg16::DI), Operand::RawWord(1), tf::sub16),
+        0x06 => specialops::push_seg(cs, SegReg::ES),
+        0x07 => specialops::pop_seg(cs, SegReg::ES),
+        0x0E => specialops::push_seg(cs, SegReg::CS),
+        0x0F => specialops::pop_seg(cs, SegReg::CS),
+        0x16 => specialops::push_seg(cs, SegReg::SS),
+        0x17 => specialops::pop_seg(cs, SegReg::SS),
+        0x1E => specialops::push_seg(cs, SegReg::DS),
+        0x1F => specialops::pop_seg(cs, SegReg::DS),
+
+        0x27 => specialops::daa(cs),
+        0x2F => specialops::das(cs),
+        0x37 => specialops::aaa(cs),
+        0x3F => specialops::aas(cs),
+
+        0x40 => op16(cs, Operand::Reg16(Reg16::AX), Operand::RawWord(1), tf::inc16),
+        0x41 => op16(cs, Operand::Reg16(Reg16::CX), Operand::RawWord(1), tf::inc16),
+        0x42 => op16(cs, Operand::Reg16(Reg16::DX), Operand::RawWord(1), tf::inc16),
+        0x43 => op16(cs, Operand::Reg16(Reg16::BX), Operand::RawWord(1), tf::inc16),
+        0x44 => op16(cs, Operand::Reg16(Reg16::SP), Operand::RawWord(1), tf::inc16),
+        0x45 => op16(cs, Operand::Reg16(Reg16::BP), Operand::RawWord(1), tf::inc16),
+        0x46 => op16(cs, Operand::Reg16(Reg16::SI), Operand::RawWord(1), tf::inc16),
+        0x47 => op16(cs, Operand::Reg16(Reg16::DI), Operand::RawWord(1), tf::inc16),
+
+        0x48 => op16(cs, Operand::Reg16(Reg16::AX), Operand::RawWord(1), tf::dec16),
+        0x49 => op16(cs, Operand::Reg16(Reg16::CX), Operand::RawWord(1), tf::dec16),
+        0x4A => op16(cs, Operand::Reg16(Reg16::DX), Operand::RawWord(1), tf::dec16),
+        0x4B => op16(cs, Operand::Reg16(Reg16::BX), Operand::RawWord(1), tf::dec16),
+        0x4C => op16(cs, Operand::Reg16(Reg16::SP), Operand::RawWord(1), tf::dec16),
+        0x4D => op16(cs, Operand::Reg16(Reg16::BP), Operand::RawWord(1), tf::dec16),
+        0x4E => op16(cs, Operand::Reg16(Reg16::SI), Operand::RawWord(1), tf::dec16),
+        0x4F => op16(cs, Operand::Reg16(Reg16::DI), Operand::RawWord(1), tf::dec16),
 
         0x50 => specialops::push(cs, Reg16::AX),
         0x51 => specialops::push(cs, Reg16::CX),
         0x52 => specialops::push(cs, Reg16::DX),
         0x53 => specialops::push(cs, Reg16::BX),
         0x54 => specialops::push(cs, Reg16::SP),
+        0x55 => specialops::push(cs, Reg16::BP),
         0x56 => specialops::push(cs, Reg16::SI),
         0x57 => specialops::push(cs, Reg16::DI),
 
@@ -274,18 +978,60 @@ fn opcode_noargs(cs: &mut CpuState, opcode: Byte) {
         0x5A => specialops::pop(cs, Reg16::DX),
         0x5B => specialops::pop(cs, Reg16::BX),
         0x5C => specialops::pop(cs, Reg16::SP),
+        0x5D => specialops::pop(cs, Reg16::BP),
         0x5E => specialops::pop(cs, Reg16::SI),
         0x5F => specialops::pop(cs, Reg16::DI),
 
         0x90 => {},
 
+        0x91 => specialops::xchg16(cs,
+                                   Operand::Reg16(Reg16::AX),
+                                   Operand::Reg16(Reg16::CX)),
         0x92 => specialops::xchg16(cs,
                                    Operand::Reg16(Reg16::AX),
                                    Operand::Reg16(Reg16::DX)),
+        0x93 => specialops::xchg16(cs,
+                                   Operand::Reg16(Reg16::AX),
+                                   Operand::Reg16(Reg16::BX)),
+        0x94 => specialops::xchg16(cs,
+                                   Operand::Reg16(Reg16::AX),
+                                   Operand::Reg16(Reg16::SP)),
+        0x95 => specialops::xchg16(cs,
+                                   Operand::Reg16(Reg16::AX),
+                                   Operand::Reg16(Reg16::BP)),
+        0x96 => specialops::xchg16(cs,
+                                   Operand::Reg16(Reg16::AX),
+                                   Operand::Reg16(Reg16::SI)),
+        0x97 => specialops::xchg16(cs,
+                                   Operand::Reg16(Reg16::AX),
+                                   Operand::Reg16(Reg16::DI)),
+
+        0x98 => specialops::cbw(cs),
+        0x99 => specialops::cwd(cs),
+        0x9B => fpu::fwait(cs),
+        0x9C => specialops::pushf(cs),
+        0x9D => specialops::popf(cs),
+        0x9E => specialops::sahf(cs),
+        0x9F => specialops::lahf(cs),
+
+        // C1 and C9 are undocumented aliases of C3 and CB
+        0xC1 | 0xC3 => specialops::ret(cs),
+        0xC9 | 0xCB => specialops::retf(cs),
+        0xCC => cs.interrupt(3),
+        0xCE => specialops::into(cs),
+        0xCF => specialops::iret(cs),
 
-        0xC3 => specialops::ret(cs),
+        0xD6 => specialops::salc(cs),
+        0xD7 => specialops::xlat(cs),
 
+        0xF4 => cs.halt(),
+        0xF5 => specialops::cmc(cs),
+        0xF8 => specialops::clc(cs),
         0xF9 => specialops::stc(cs),
+        0xFA => specialops::cli(cs),
+        0xFB => specialops::sti(cs),
+        0xFC => specialops::cld(cs),
+        0xFD => specialops::std(cs),
 
         _ => panic!("Invalid opcode"),
     };
@@ -1,6 +1,5 @@
 use std::num::ToPrimitive;
 use cstate;
-use byteutils;
 use datatypes::{Byte, Word};
 
 
@@ -9,7 +8,8 @@ pub enum Operand {
     RawWord(Word),
     Reg8(cstate::Reg8),
     Reg16(cstate::Reg16),
-    MemoryAddress(Word),
+    SegReg(cstate::SegReg),
+    MemoryAddress(cstate::SegReg, Word),
 }
 
 
@@ -18,6 +18,8 @@ pub struct Flags {
     pub overflow: bool,
     pub sign: bool,
     pub zero: bool,
+    pub aux_carry: bool,
+    pub parity: bool,
 }
 
 
@@ -27,7 +29,8 @@ pub fn operand_value8(cs: &mut cstate::CpuState, o: &Operand) -> Byte {
         Operand::RawWord(_) => panic!("invalid"),
         Operand::Reg8(ref reg) => cs.getreg8(reg),
         Operand::Reg16(_) => panic!("invalid"),
-        Operand::MemoryAddress(ref addr) => cs.getmem(*addr),
+        Operand::SegReg(_) => panic!("invalid"),
+        Operand::MemoryAddress(ref seg, ref addr) => cs.getmem(seg, *addr),
     }
 }
 
@@ -37,9 +40,8 @@ pub fn operand_value16(cs: &mut cstate::CpuState, o: &Operand) -> Word {
         Operand::RawWord(ref v) => *v,
         Operand::Reg8(_) => panic!("invalid!"),
         Operand::Reg16(ref reg) => cs.getreg16(reg),
-        Operand::MemoryAddress(ref addr) => {
-            byteutils::join8(cs.getmem(*addr + 1), cs.getmem(*addr))
-        }
+        Operand::SegReg(ref reg) => cs.getseg(reg),
+        Operand::MemoryAddress(ref seg, ref addr) => cs.getmem16(seg, *addr),
     }
 }
 
@@ -49,7 +51,8 @@ pub fn operand_set8(cs: &mut cstate::CpuState, o: &Operand, result: Byte) {
         Operand::RawWord(_) => panic!("invalid"),
         Operand::Reg8(ref reg) => cs.setreg8(reg, result),
         Operand::Reg16(ref reg) => cs.setreg16(reg, result.to_u16().unwrap()),
-        Operand::MemoryAddress(ref addr) => cs.setmem(*addr, result),
+        Operand::SegReg(_) => panic!("invalid"),
+        Operand::MemoryAddress(ref seg, ref addr) => cs.setmem(seg, *addr, result),
     }
 }
 
@@ -59,9 +62,26 @@ pub fn operand_set16(cs: &mut cstate::CpuState, o: &Operand, result: Word) {
         Operand::RawWord(_) => panic!("invalid"),
         Operand::Reg8(_) => panic!("invalid"),
         Operand::Reg16(ref reg) => cs.setreg16(reg, result),
-        Operand::MemoryAddress(ref addr) => {
-            cs.setmem(*addr, byteutils::high8(result));
-            cs.setmem(*addr + 1, byteutils::low8(result));
+        Operand::SegReg(ref reg) => cs.setseg(reg, result),
+        Operand::MemoryAddress(ref seg, ref addr) => cs.setmem16(seg, *addr, result),
+    }
+}
+
+/// Read a far pointer, stored as an offset followed by a segment, from a
+/// memory operand. Returns (offset, segment).
+pub fn operand_far_pointer(cs: &mut cstate::CpuState, o: &Operand) -> (Word, Word) {
+    return match *o {
+        Operand::MemoryAddress(ref seg, ref addr) => {
+            (cs.getmem16(seg, *addr), cs.getmem16(seg, *addr + 2))
         }
+        _ => panic!("invalid"),
+    }
+}
+
+/// Return the effective offset of a memory operand, as LEA does
+pub fn operand_address(o: &Operand) -> Word {
+    return match *o {
+        Operand::MemoryAddress(_, ref addr) => *addr,
+        _ => panic!("invalid"),
     }
 }
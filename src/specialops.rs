@@ -1,6 +1,8 @@
 use std::num::ToPrimitive;
-use cstate::{CpuState, Reg16};
+use byteutils::{sign_extend8, low8, high8, join8, parity};
+use cstate::{CpuState, CpuModel, Reg8, Reg16, SegReg};
 use datatypes::{Byte, Word};
+use tf;
 use operand::{
     Operand,
     operand_value8,
@@ -10,11 +12,13 @@ use operand::{
 };
 
 
-pub type FlagFn = fn(&CpuState) -> bool;
-
-
+/// PUSH reg. The 8086 decrements SP before reading the register, so
+/// PUSH SP pushes the new value of SP.
 pub fn push(cs: &mut CpuState, reg: Reg16) {
-    let cur_val = cs.getreg16(&reg);
+    let cur_val = match reg {
+        Reg16::SP => cs.getreg16(&reg) - 2,
+        _ => cs.getreg16(&reg),
+    };
     cs.push(cur_val);
 }
 
@@ -23,17 +27,170 @@ pub fn pop(cs: &mut CpuState, reg: Reg16) {
     cs.setreg16(&reg, popped_val);
 }
 
+/// PUSH Ev, with the same PUSH SP behaviour as `push'
+pub fn push_operand(cs: &mut CpuState, src: &Operand) {
+    match *src {
+        Operand::Reg16(Reg16::SP) => push(cs, Reg16::SP),
+        _ => {
+            let val = operand_value16(cs, src);
+            cs.push(val);
+        },
+    }
+}
+
+/// PUSHA: push AX, CX, DX, BX, the original SP, BP, SI and DI
+pub fn pusha(cs: &mut CpuState) {
+    let sp = cs.getreg16(&Reg16::SP);
+    let regs = [Reg16::AX, Reg16::CX, Reg16::DX, Reg16::BX,
+                Reg16::SP, Reg16::BP, Reg16::SI, Reg16::DI];
+    for reg in regs.iter() {
+        let val = match *reg {
+            Reg16::SP => sp,
+            _ => cs.getreg16(reg),
+        };
+        cs.push(val);
+    }
+}
+
+/// POPA: the reverse of PUSHA. The saved SP is discarded.
+pub fn popa(cs: &mut CpuState) {
+    let regs = [Reg16::DI, Reg16::SI, Reg16::BP, Reg16::SP,
+                Reg16::BX, Reg16::DX, Reg16::CX, Reg16::AX];
+    for reg in regs.iter() {
+        let val = cs.pop();
+        match *reg {
+            Reg16::SP => {},
+            _ => cs.setreg16(reg, val),
+        }
+    }
+}
+
+pub fn push_seg(cs: &mut CpuState, reg: SegReg) {
+    let cur_val = cs.getseg(&reg);
+    cs.push(cur_val);
+}
+
+/// POP Sreg. Popping into SS holds off interrupts for one instruction so
+/// that SS and SP can be loaded together.
+pub fn pop_seg(cs: &mut CpuState, reg: SegReg) {
+    let popped_val = cs.pop();
+    cs.setseg(&reg, popped_val);
+    if reg == SegReg::SS {
+        cs.inhibit_interrupts();
+    }
+}
+
+/// MOV Sreg, Ew. Like POP SS, loading SS holds off interrupts.
+pub fn mov_seg(cs: &mut CpuState, reg: SegReg, src: Operand) {
+    let val = operand_value16(cs, &src);
+    cs.setseg(&reg, val);
+    if reg == SegReg::SS {
+        cs.inhibit_interrupts();
+    }
+}
+
+/// ENTER size, level: push BP, copy `level - 1' frame pointers from the
+/// enclosing frame plus a pointer to the new one, point BP at the new
+/// frame and reserve `size' bytes. Only the low five bits of `level' count.
+pub fn enter(cs: &mut CpuState, size: Word, level: Byte) {
+    let level = level & 0x1F;
+    let bp = cs.getreg16(&Reg16::BP);
+    cs.push(bp);
+    let frame = cs.getreg16(&Reg16::SP);
+
+    if level > 0 {
+        let mut outer = bp;
+        for _ in range(1, level) {
+            outer = outer - 2;
+            let val = cs.getmem16(&SegReg::SS, outer);
+            cs.push(val);
+        }
+        cs.push(frame);
+    }
+
+    cs.setreg16(&Reg16::BP, frame);
+    cs.setreg16(&Reg16::SP, frame - size);
+}
+
+/// LEAVE: discard the frame built by ENTER
+pub fn leave(cs: &mut CpuState) {
+    let bp = cs.getreg16(&Reg16::BP);
+    cs.setreg16(&Reg16::SP, bp);
+    let val = cs.pop();
+    cs.setreg16(&Reg16::BP, val);
+}
+
+/// BOUND r16, m16&16: raise a type 5 interrupt unless the signed index in
+/// `index' lies within the bounds stored at `seg':`addr'. The saved IP
+/// points at the BOUND instruction.
+pub fn bound(cs: &mut CpuState, index: Operand, seg: SegReg, addr: Word) {
+    let index = operand_value16(cs, &index) as i16;
+    let lower = cs.getmem16(&seg, addr) as i16;
+    let upper = cs.getmem16(&seg, addr + 2) as i16;
+    if index < lower || index > upper {
+        cs.restart_instruction();
+        cs.interrupt(5);
+    }
+}
+
 pub fn call(cs: &mut CpuState, immediate: Word) {
     let ip = cs.getreg16(&Reg16::IP);
     cs.push(ip);
     jmp16(cs, immediate);
 }
 
+/// Call an absolute offset in the current code segment
+pub fn call_near(cs: &mut CpuState, target: Word) {
+    let ip = cs.getreg16(&Reg16::IP);
+    cs.push(ip);
+    cs.setreg16(&Reg16::IP, target);
+}
+
+/// Call segment:offset, pushing CS and then IP
+pub fn call_far(cs: &mut CpuState, segment: Word, offset: Word) {
+    let code_seg = cs.getseg(&SegReg::CS);
+    cs.push(code_seg);
+    call_near(cs, offset);
+    cs.setseg(&SegReg::CS, segment);
+}
+
 pub fn ret(cs: &mut CpuState) {
     let ip = cs.pop();
     cs.setreg16(&Reg16::IP, ip);
 }
 
+/// RET imm16: return, then discard `release' bytes of arguments
+pub fn ret_release(cs: &mut CpuState, release: Word) {
+    ret(cs);
+    let sp = cs.getreg16(&Reg16::SP);
+    cs.setreg16(&Reg16::SP, sp + release);
+}
+
+/// RETF: pop IP and then CS
+pub fn retf(cs: &mut CpuState) {
+    ret(cs);
+    let code_seg = cs.pop();
+    cs.setseg(&SegReg::CS, code_seg);
+}
+
+/// RETF imm16: far return, then discard `release' bytes of arguments
+pub fn retf_release(cs: &mut CpuState, release: Word) {
+    retf(cs);
+    let sp = cs.getreg16(&Reg16::SP);
+    cs.setreg16(&Reg16::SP, sp + release);
+}
+
+/// IRET: pop IP, CS and FLAGS, undoing `CpuState::interrupt'. On the V20
+/// this also restores the MD flag, so it can return to 8080 emulation.
+pub fn iret(cs: &mut CpuState) {
+    retf(cs);
+    let flags = cs.pop();
+    cs.set_flags_word(flags);
+    if cs.model() == CpuModel::V20 {
+        cs.set_emulation_mode(flags & 0x8000 == 0);
+    }
+}
+
 pub fn xchg8(cs: &mut CpuState, left: Operand, right: Operand) {
     let left_val = operand_value8(cs, &left);
     let right_val = operand_value8(cs, &right);
@@ -50,12 +207,7 @@ pub fn xchg16(cs: &mut CpuState, left: Operand, right: Operand) {
 
 pub fn jmp8(cs: &mut CpuState, offset: Byte) {
     let ip = cs.getreg16(&Reg16::IP);
-    let offset = offset.to_u16().unwrap();
-    if offset < 127 {
-        cs.setreg16(&Reg16::IP, ip + offset);
-    } else {
-        cs.setreg16(&Reg16::IP, ip - (256 - offset));
-    }
+    cs.setreg16(&Reg16::IP, ip + sign_extend8(offset));
 }
 
 pub fn jmp16(cs: &mut CpuState, offset: Word) {
@@ -63,28 +215,389 @@ pub fn jmp16(cs: &mut CpuState, offset: Word) {
     cs.setreg16(&Reg16::IP, ip + offset);
 }
 
-pub fn jmp_flag(cs: &mut CpuState, flag_fn: FlagFn, invert: bool, immediate: Byte) {
-    let flag_value = flag_fn(cs);
-    if !(flag_value ^ invert) {
-        return
-    }
+pub fn jmp_far(cs: &mut CpuState, segment: Word, offset: Word) {
+    cs.setseg(&SegReg::CS, segment);
+    cs.setreg16(&Reg16::IP, offset);
+}
 
-    jmp8(cs, immediate);
+/// Jump by `offset' if condition code `cc' holds
+pub fn jcc(cs: &mut CpuState, cc: Byte, offset: Byte) {
+    if cs.condition(cc) {
+        jmp8(cs, offset);
+    }
 }
 
-pub fn jmp_flags(cs: &mut CpuState, 
-                 flag0_fn: FlagFn,
-                 flag1_fn: FlagFn,
-                 invert: bool,
-                 immediate: Byte) {
-    let flags_value = flag0_fn(cs) || flag1_fn(cs);
-    if !(flags_value ^ invert) {
-        return;
+/// LOOP, and LOOPE/LOOPNE when `zero' is given: decrement CX without
+/// touching the flags, and jump by `offset' if CX is now non-zero and ZF
+/// equals `zero'.
+pub fn loop_cx(cs: &mut CpuState, zero: Option<bool>, offset: Byte) {
+    let cx = cs.getreg16(&Reg16::CX) - 1;
+    cs.setreg16(&Reg16::CX, cx);
+
+    let zero_matches = match zero {
+        Some(zf) => cs.zero() == zf,
+        None => true,
+    };
+    if cx != 0 && zero_matches {
+        jmp8(cs, offset);
     }
+}
 
-    jmp8(cs, immediate);
+pub fn jcxz(cs: &mut CpuState, offset: Byte) {
+    if cs.getreg16(&Reg16::CX) == 0 {
+        jmp8(cs, offset);
+    }
 }
 
 pub fn stc(cs: &mut CpuState) {
     cs.set_carry();
 }
+
+pub fn clc(cs: &mut CpuState) {
+    let mut flags = cs.get_flags();
+    flags.carry = false;
+    cs.set_flags(flags);
+}
+
+pub fn cmc(cs: &mut CpuState) {
+    let mut flags = cs.get_flags();
+    flags.carry = !flags.carry;
+    cs.set_flags(flags);
+}
+
+pub fn cld(cs: &mut CpuState) {
+    cs.set_direction(false);
+}
+
+pub fn std(cs: &mut CpuState) {
+    cs.set_direction(true);
+}
+
+pub fn cli(cs: &mut CpuState) {
+    cs.set_interrupts_enabled(false);
+}
+
+/// STI. Interrupts are not recognised until after the next instruction.
+pub fn sti(cs: &mut CpuState) {
+    cs.set_interrupts_enabled(true);
+    cs.inhibit_interrupts();
+}
+
+/// INTO: raise interrupt 4 if OF is set
+pub fn into(cs: &mut CpuState) {
+    if cs.condition(0x0) {
+        cs.interrupt(4);
+    }
+}
+
+/// Set flags after a multiply. CF and OF report whether the high half of
+/// the product is significant. SF, ZF and PF are undefined on the 8086;
+/// here they follow the high half, and AF is cleared.
+fn set_mul_flags(cs: &mut CpuState, significant: bool, high: Word, sign: bool) {
+    let mut flags = cs.get_flags();
+    flags.carry = significant;
+    flags.overflow = significant;
+    flags.sign = sign;
+    flags.zero = high == 0;
+    flags.aux_carry = false;
+    flags.parity = parity(high);
+    cs.set_flags(flags);
+}
+
+/// Raise a type 0 divide error. As on the 8086, the saved IP points past
+/// the faulting instruction.
+fn divide_error(cs: &mut CpuState) {
+    cs.interrupt(0);
+}
+
+/// MUL r/m8: AX = AL * src
+pub fn mul8(cs: &mut CpuState, src: Byte) {
+    let al = cs.getreg8(&Reg8::AL).to_u16().unwrap();
+    let result = al * src.to_u16().unwrap();
+    cs.setreg16(&Reg16::AX, result);
+
+    let high = result >> 8;
+    set_mul_flags(cs, high != 0, high, high & 0x80 != 0);
+}
+
+/// IMUL r/m8: AX = AL * src, signed
+pub fn imul8(cs: &mut CpuState, src: Byte) {
+    let al = cs.getreg8(&Reg8::AL) as i8 as i16;
+    let result = (al * (src as i8 as i16)) as Word;
+    cs.setreg16(&Reg16::AX, result);
+
+    let high = result >> 8;
+    let significant = result != sign_extend8(high8(result));
+    set_mul_flags(cs, significant, high, high & 0x80 != 0);
+}
+
+/// MUL r/m16: DX:AX = AX * src
+pub fn mul16(cs: &mut CpuState, src: Word) {
+    let ax = cs.getreg16(&Reg16::AX).to_u32().unwrap();
+    let result = ax * src.to_u32().unwrap();
+    let high = (result >> 16) as Word;
+    cs.setreg16(&Reg16::AX, result as Word);
+    cs.setreg16(&Reg16::DX, high);
+
+    set_mul_flags(cs, high != 0, high, high & 0x8000 != 0);
+}
+
+/// IMUL r/m16: DX:AX = AX * src, signed
+pub fn imul16(cs: &mut CpuState, src: Word) {
+    let ax = cs.getreg16(&Reg16::AX) as i16 as i32;
+    let result = ax * (src as i16 as i32);
+    let low = result as Word;
+    let high = (result >> 16) as Word;
+    cs.setreg16(&Reg16::AX, low);
+    cs.setreg16(&Reg16::DX, high);
+
+    let significant = result != (low as i16 as i32);
+    set_mul_flags(cs, significant, high, high & 0x8000 != 0);
+}
+
+/// IMUL r16, r/m16, imm: `dest' = src * immediate, signed, keeping only
+/// the low half of the product
+pub fn imul_immediate(cs: &mut CpuState, dest: Operand, src: Word, immediate: Word) {
+    let result = (src as i16 as i32) * (immediate as i16 as i32);
+    let low = result as Word;
+    let high = (result >> 16) as Word;
+    operand_set16(cs, &dest, low);
+
+    let significant = result != (low as i16 as i32);
+    set_mul_flags(cs, significant, high, high & 0x8000 != 0);
+}
+
+/// DIV r/m8: AL = AX / src, AH = AX % src
+pub fn div8(cs: &mut CpuState, src: Byte) {
+    let ax = cs.getreg16(&Reg16::AX);
+    let divisor = src.to_u16().unwrap();
+    if divisor == 0 || ax / divisor > 0xFF {
+        return divide_error(cs);
+    }
+
+    cs.setreg8(&Reg8::AL, (ax / divisor) as Byte);
+    cs.setreg8(&Reg8::AH, (ax % divisor) as Byte);
+}
+
+/// IDIV r/m8: signed AL = AX / src, AH = AX % src. The 8086 only accepts
+/// quotients from -127 to 127; -128 raises a divide error.
+pub fn idiv8(cs: &mut CpuState, src: Byte) {
+    let dividend = cs.getreg16(&Reg16::AX) as i16 as i32;
+    let divisor = src as i8 as i32;
+    if divisor == 0 {
+        return divide_error(cs);
+    }
+
+    let quotient = dividend / divisor;
+    if quotient > 127 || quotient < -127 {
+        return divide_error(cs);
+    }
+
+    cs.setreg8(&Reg8::AL, quotient as Byte);
+    cs.setreg8(&Reg8::AH, (dividend % divisor) as Byte);
+}
+
+/// DIV r/m16: AX = DX:AX / src, DX = DX:AX % src
+pub fn div16(cs: &mut CpuState, src: Word) {
+    let dx = cs.getreg16(&Reg16::DX).to_u32().unwrap();
+    let ax = cs.getreg16(&Reg16::AX).to_u32().unwrap();
+    let dividend = (dx << 16) | ax;
+    let divisor = src.to_u32().unwrap();
+    if divisor == 0 || dividend / divisor > 0xFFFF {
+        return divide_error(cs);
+    }
+
+    cs.setreg16(&Reg16::AX, (dividend / divisor) as Word);
+    cs.setreg16(&Reg16::DX, (dividend % divisor) as Word);
+}
+
+/// IDIV r/m16: signed AX = DX:AX / src, DX = DX:AX % src. As with the byte
+/// form, the most negative quotient raises a divide error on the 8086.
+pub fn idiv16(cs: &mut CpuState, src: Word) {
+    let dx = cs.getreg16(&Reg16::DX).to_u32().unwrap();
+    let ax = cs.getreg16(&Reg16::AX).to_u32().unwrap();
+    let dividend = ((dx << 16) | ax) as i32 as i64;
+    let divisor = src as i16 as i64;
+    if divisor == 0 {
+        return divide_error(cs);
+    }
+
+    let quotient = dividend / divisor;
+    if quotient > 32767 || quotient < -32767 {
+        return divide_error(cs);
+    }
+
+    cs.setreg16(&Reg16::AX, quotient as Word);
+    cs.setreg16(&Reg16::DX, (dividend % divisor) as Word);
+}
+
+/// DAA. On the 8086 the high digit is corrected when AL is above 0x99,
+/// or above 0x9F if AF was set on entry. SF, ZF, PF and OF are those of
+/// the correcting addition.
+pub fn daa(cs: &mut CpuState) {
+    let al = cs.getreg8(&Reg8::AL);
+    let flags = cs.get_flags();
+    let threshold = if flags.aux_carry { 0x9F } else { 0x99 };
+
+    let mut correction: Byte = 0;
+    let aux_carry = (al & 0x0F) > 9 || flags.aux_carry;
+    if aux_carry {
+        correction = correction + 0x06;
+    }
+    let carry = al > threshold || flags.carry;
+    if carry {
+        correction = correction + 0x60;
+    }
+
+    let (result, mut flags) = tf::add8(al, correction, flags);
+    flags.carry = carry;
+    flags.aux_carry = aux_carry;
+    cs.setreg8(&Reg8::AL, result);
+    cs.set_flags(flags);
+}
+
+/// DAS. The counterpart of DAA for subtraction, with the same 8086
+/// threshold behaviour.
+pub fn das(cs: &mut CpuState) {
+    let al = cs.getreg8(&Reg8::AL);
+    let flags = cs.get_flags();
+    let threshold = if flags.aux_carry { 0x9F } else { 0x99 };
+
+    let mut correction: Byte = 0;
+    let aux_carry = (al & 0x0F) > 9 || flags.aux_carry;
+    if aux_carry {
+        correction = correction + 0x06;
+    }
+    let carry = al > threshold || flags.carry;
+    if carry {
+        correction = correction + 0x60;
+    }
+
+    let (result, mut flags) = tf::sub8(al, correction, flags);
+    flags.carry = carry;
+    flags.aux_carry = aux_carry;
+    cs.setreg8(&Reg8::AL, result);
+    cs.set_flags(flags);
+}
+
+/// AAA. Unlike later CPUs, the 8086 adds 6 to AL alone and increments AH
+/// separately. SF, ZF, PF and OF are those of the addition to AL, before
+/// the high nibble is cleared.
+pub fn aaa(cs: &mut CpuState) {
+    let al = cs.getreg8(&Reg8::AL);
+    let flags = cs.get_flags();
+    let adjust = (al & 0x0F) > 9 || flags.aux_carry;
+
+    let correction = if adjust { 6 } else { 0 };
+    let (result, mut flags) = tf::add8(al, correction, flags);
+    if adjust {
+        let ah = cs.getreg8(&Reg8::AH);
+        cs.setreg8(&Reg8::AH, ah + 1);
+    }
+
+    flags.carry = adjust;
+    flags.aux_carry = adjust;
+    cs.setreg8(&Reg8::AL, result & 0x0F);
+    cs.set_flags(flags);
+}
+
+/// AAS. The counterpart of AAA for subtraction.
+pub fn aas(cs: &mut CpuState) {
+    let al = cs.getreg8(&Reg8::AL);
+    let flags = cs.get_flags();
+    let adjust = (al & 0x0F) > 9 || flags.aux_carry;
+
+    let correction = if adjust { 6 } else { 0 };
+    let (result, mut flags) = tf::sub8(al, correction, flags);
+    if adjust {
+        let ah = cs.getreg8(&Reg8::AH);
+        cs.setreg8(&Reg8::AH, ah - 1);
+    }
+
+    flags.carry = adjust;
+    flags.aux_carry = adjust;
+    cs.setreg8(&Reg8::AL, result & 0x0F);
+    cs.set_flags(flags);
+}
+
+/// AAM: AH = AL / base, AL = AL % base. A base of 0 raises a divide
+/// error. SF, ZF and PF follow the new AL; CF, OF and AF are cleared.
+pub fn aam(cs: &mut CpuState, base: Byte) {
+    if base == 0 {
+        return divide_error(cs);
+    }
+
+    let al = cs.getreg8(&Reg8::AL);
+    cs.setreg8(&Reg8::AH, al / base);
+
+    let flags = cs.get_flags();
+    let (result, flags) = tf::add8(al % base, 0, flags);
+    cs.setreg8(&Reg8::AL, result);
+    cs.set_flags(flags);
+}
+
+/// AAD: AL = AL + AH * base, AH = 0, for any base. The flags are those of
+/// the final addition, which is how the 8086 computes them.
+pub fn aad(cs: &mut CpuState, base: Byte) {
+    let al = cs.getreg8(&Reg8::AL);
+    let ah = cs.getreg8(&Reg8::AH).to_u16().unwrap();
+    let product = (ah * base.to_u16().unwrap()) as Byte;
+
+    let flags = cs.get_flags();
+    let (result, flags) = tf::add8(al, product, flags);
+    cs.setreg8(&Reg8::AL, result);
+    cs.setreg8(&Reg8::AH, 0);
+    cs.set_flags(flags);
+}
+
+/// SALC (undocumented): set AL to 0xFF if CF is set, or 0 otherwise,
+/// without changing the flags
+pub fn salc(cs: &mut CpuState) {
+    let al = if cs.carry() { 0xFF } else { 0 };
+    cs.setreg8(&Reg8::AL, al);
+}
+
+/// XLAT: AL = [DS:BX + AL], honouring segment overrides
+pub fn xlat(cs: &mut CpuState) {
+    let seg = cs.segment(SegReg::DS);
+    let al = cs.getreg8(&Reg8::AL).to_u16().unwrap();
+    let addr = cs.getreg16(&Reg16::BX) + al;
+    let val = cs.getmem(&seg, addr);
+    cs.setreg8(&Reg8::AL, val);
+}
+
+/// CBW: sign-extend AL into AX
+pub fn cbw(cs: &mut CpuState) {
+    let al = cs.getreg8(&Reg8::AL);
+    cs.setreg16(&Reg16::AX, sign_extend8(al));
+}
+
+/// CWD: sign-extend AX into DX:AX
+pub fn cwd(cs: &mut CpuState) {
+    let ax = cs.getreg16(&Reg16::AX);
+    let dx = if ax & 0x8000 != 0 { 0xFFFF } else { 0 };
+    cs.setreg16(&Reg16::DX, dx);
+}
+
+pub fn pushf(cs: &mut CpuState) {
+    let flags = cs.get_flags_word();
+    cs.push(flags);
+}
+
+pub fn popf(cs: &mut CpuState) {
+    let flags = cs.pop();
+    cs.set_flags_word(flags);
+}
+
+/// SAHF: load SF, ZF, AF, PF and CF from AH
+pub fn sahf(cs: &mut CpuState) {
+    let flags = cs.get_flags_word();
+    let ah = cs.getreg8(&Reg8::AH);
+    cs.set_flags_word(join8(low8(flags), ah));
+}
+
+/// LAHF: store the low byte of FLAGS in AH
+pub fn lahf(cs: &mut CpuState) {
+    let flags = cs.get_flags_word();
+    cs.setreg8(&Reg8::AH, high8(flags));
+}
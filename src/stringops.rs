@@ -0,0 +1,159 @@
+use cstate::{CpuState, Reg8, Reg16, SegReg, RepPrefix};
+use datatypes::{Byte, Word};
+use operation::{op8, op16, op8_dry, op16_dry};
+use operand::{
+    Operand,
+    operand_value8,
+    operand_set8,
+    operand_value16,
+    operand_set16,
+};
+use tf;
+
+
+/// String instructions A4-A7 and AA-AF, and the 80186's INS/OUTS at
+/// 6C-6F. Each call performs a single
+/// iteration. Under a REP prefix, CX is decremented and the instruction is
+/// restarted with `CpuState::repeat_instruction' until it terminates, so
+/// interrupts are recognised between iterations.
+pub fn string_op(cs: &mut CpuState, opcode: Byte) {
+    let rep = cs.rep_prefix();
+    if rep.is_some() && cs.getreg16(&Reg16::CX) == 0 {
+        return;
+    }
+
+    let word = opcode & 1 == 1;
+    let compares = match opcode {
+        0xA4 | 0xA5 => { movs(cs, word); false },
+        0xA6 | 0xA7 => { cmps(cs, word); true },
+        0xAA | 0xAB => { stos(cs, word); false },
+        0xAC | 0xAD => { lods(cs, word); false },
+        0xAE | 0xAF => { scas(cs, word); true },
+        0x6C | 0x6D => { ins(cs, word); false },
+        0x6E | 0x6F => { outs(cs, word); false },
+        _ => panic!("Invalid opcode"),
+    };
+
+    let rep = match rep {
+        Some(rep) => rep,
+        None => return,
+    };
+
+    let cx = cs.getreg16(&Reg16::CX) - 1;
+    cs.setreg16(&Reg16::CX, cx);
+
+    // CMPS and SCAS also stop as soon as ZF, or CF for the V20's REPC
+    // and REPNC, disagrees with the prefix
+    let finished = cx == 0 || (compares && match rep {
+        RepPrefix::RepE => !cs.zero(),
+        RepPrefix::RepNE => cs.zero(),
+        RepPrefix::RepC => !cs.carry(),
+        RepPrefix::RepNC => cs.carry(),
+    });
+    if !finished {
+        cs.repeat_instruction();
+    }
+}
+
+/// Move `reg' on to the next element, backwards if DF is set
+fn advance(cs: &mut CpuState, reg: Reg16, word: bool) {
+    let size: Word = if word { 2 } else { 1 };
+    let val = cs.getreg16(&reg);
+    if cs.direction() {
+        cs.setreg16(&reg, val - size);
+    } else {
+        cs.setreg16(&reg, val + size);
+    }
+}
+
+/// DS:SI, or another segment if overridden
+fn source(cs: &CpuState) -> Operand {
+    Operand::MemoryAddress(cs.segment(SegReg::DS), cs.getreg16(&Reg16::SI))
+}
+
+/// ES:DI, which cannot be overridden
+fn destination(cs: &CpuState) -> Operand {
+    Operand::MemoryAddress(SegReg::ES, cs.getreg16(&Reg16::DI))
+}
+
+fn movs(cs: &mut CpuState, word: bool) {
+    let src = source(cs);
+    let dest = destination(cs);
+    if word {
+        op16(cs, dest, src, tf::noop16);
+    } else {
+        op8(cs, dest, src, tf::noop8);
+    }
+    advance(cs, Reg16::SI, word);
+    advance(cs, Reg16::DI, word);
+}
+
+fn cmps(cs: &mut CpuState, word: bool) {
+    let src = source(cs);
+    let dest = destination(cs);
+    if word {
+        op16_dry(cs, src, dest, tf::sub16);
+    } else {
+        op8_dry(cs, src, dest, tf::sub8);
+    }
+    advance(cs, Reg16::SI, word);
+    advance(cs, Reg16::DI, word);
+}
+
+fn stos(cs: &mut CpuState, word: bool) {
+    let dest = destination(cs);
+    if word {
+        op16(cs, dest, Operand::Reg16(Reg16::AX), tf::noop16);
+    } else {
+        op8(cs, dest, Operand::Reg8(Reg8::AL), tf::noop8);
+    }
+    advance(cs, Reg16::DI, word);
+}
+
+fn lods(cs: &mut CpuState, word: bool) {
+    let src = source(cs);
+    if word {
+        op16(cs, Operand::Reg16(Reg16::AX), src, tf::noop16);
+    } else {
+        op8(cs, Operand::Reg8(Reg8::AL), src, tf::noop8);
+    }
+    advance(cs, Reg16::SI, word);
+}
+
+fn scas(cs: &mut CpuState, word: bool) {
+    let dest = destination(cs);
+    if word {
+        op16_dry(cs, Operand::Reg16(Reg16::AX), dest, tf::sub16);
+    } else {
+        op8_dry(cs, Operand::Reg8(Reg8::AL), dest, tf::sub8);
+    }
+    advance(cs, Reg16::DI, word);
+}
+
+/// INS: read from port DX into ES:DI
+fn ins(cs: &mut CpuState, word: bool) {
+    let port = cs.getreg16(&Reg16::DX);
+    let dest = destination(cs);
+    if word {
+        let val = cs.port_in16(port);
+        operand_set16(cs, &dest, val);
+    } else {
+        let val = cs.port_in8(port);
+        operand_set8(cs, &dest, val);
+    }
+    advance(cs, Reg16::DI, word);
+}
+
+/// OUTS: write DS:SI, or an overridden segment, to port DX
+fn outs(cs: &mut CpuState, word: bool) {
+    let port = cs.getreg16(&Reg16::DX);
+    let src = source(cs);
+    if word {
+        let val = operand_value16(cs, &src);
+        cs.port_out16(port, val);
+    } else {
+        let val = operand_value8(cs, &src);
+        cs.port_out8(port, val);
+    }
+    advance(cs, Reg16::SI, word);
+}
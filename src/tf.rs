@@ -1,4 +1,6 @@
+use std::num::ToPrimitive;
 use byteutils;
+use byteutils::ShiftOp;
 use operand::Flags;
 use datatypes::{Byte, Word};
 
@@ -7,47 +9,148 @@ macro_rules! define_transform (
     (
         $name:ident,
         $size:ident,
-        $arithmetic_fn:expr
+        $arithmetic_fn:expr,
+        $carry_fn:ident
     ) => {
-        pub fn $name(left: $size, right: $size, _: Flags) -> ($size, Flags) {
-            let (result, cf, of, sf, zf) = $arithmetic_fn(left, right);
+        pub fn $name(left: $size, right: $size, flags: Flags) -> ($size, Flags) {
+            let carry_in = $carry_fn(&flags);
+            let (result, cf, of, sf, zf, af, pf) = $arithmetic_fn(left, right, carry_in);
             let flags = Flags {
                 carry: cf,
                 overflow: of,
                 sign: sf,
                 zero: zf,
+                aux_carry: af,
+                parity: pf,
             };
             (result, flags)
         }
     }
 );
 
-define_transform!(add8, Byte, byteutils::add8);
-define_transform!(sub8, Byte, byteutils::sub8);
-define_transform!(or8,  Byte, byteutils::or8);
+fn no_carry(_: &Flags) -> bool {
+    false
+}
+
+fn with_carry(flags: &Flags) -> bool {
+    flags.carry
+}
+
+define_transform!(add8, Byte, byteutils::add8, no_carry);
+define_transform!(sub8, Byte, byteutils::sub8, no_carry);
+define_transform!(or8,  Byte, byteutils::or8,  no_carry);
+define_transform!(xor8, Byte, byteutils::xor8, no_carry);
+define_transform!(and8, Byte, byteutils::and8, no_carry);
+define_transform!(adc8, Byte, byteutils::add8, with_carry);
+define_transform!(sbb8, Byte, byteutils::sub8, with_carry);
+
+define_transform!(add16, Word, byteutils::add16, no_carry);
+define_transform!(sub16, Word, byteutils::sub16, no_carry);
+define_transform!(or16,  Word, byteutils::or16,  no_carry);
+define_transform!(xor16, Word, byteutils::xor16, no_carry);
+define_transform!(and16, Word, byteutils::and16, no_carry);
+define_transform!(adc16, Word, byteutils::add16, with_carry);
+define_transform!(sbb16, Word, byteutils::sub16, with_carry);
+
+/// INC and DEC are ADD and SUB of 1 that leave CF untouched. `right' is
+/// ignored.
+pub fn inc8(left: Byte, _: Byte, flags: Flags) -> (Byte, Flags) {
+    let carry = flags.carry;
+    let (result, mut flags) = add8(left, 1, flags);
+    flags.carry = carry;
+    (result, flags)
+}
 
-define_transform!(add16, Word, byteutils::add16);
-define_transform!(sub16, Word, byteutils::sub16);
-define_transform!(or16,  Word, byteutils::or16);
-define_transform!(xor16, Word, byteutils::xor16);
-define_transform!(and16, Word, byteutils::and16);
+pub fn dec8(left: Byte, _: Byte, flags: Flags) -> (Byte, Flags) {
+    let carry = flags.carry;
+    let (result, mut flags) = sub8(left, 1, flags);
+    flags.carry = carry;
+    (result, flags)
+}
+
+pub fn inc16(left: Word, _: Word, flags: Flags) -> (Word, Flags) {
+    let carry = flags.carry;
+    let (result, mut flags) = add16(left, 1, flags);
+    flags.carry = carry;
+    (result, flags)
+}
+
+pub fn dec16(left: Word, _: Word, flags: Flags) -> (Word, Flags) {
+    let carry = flags.carry;
+    let (result, mut flags) = sub16(left, 1, flags);
+    flags.carry = carry;
+    (result, flags)
+}
+
+pub fn not8(left: Byte, _: Byte, flags: Flags) -> (Byte, Flags) {
+    (!left, flags)
+}
 
-pub fn sbb16(left: Word, right: Word, flags: Flags) -> (Word, Flags) {
-    let carry = match flags.carry {
-        true => 1,
-        false => 0,
-    };
-    sub16(left, right + carry, flags)
+pub fn not16(left: Word, _: Word, flags: Flags) -> (Word, Flags) {
+    (!left, flags)
 }
 
-pub fn adc16(left: Word, right: Word, flags: Flags) -> (Word, Flags) {
-    let carry = match flags.carry {
-        true => 1,
-        false => 0,
-    };
-    add16(left, right + carry, flags)
+pub fn neg8(left: Byte, _: Byte, flags: Flags) -> (Byte, Flags) {
+    sub8(0, left, flags)
 }
 
+pub fn neg16(left: Word, _: Word, flags: Flags) -> (Word, Flags) {
+    sub16(0, left, flags)
+}
+
+/// Shift and rotate transforms. `right' is the shift count. Rotates only
+/// affect CF and OF; shifts also set SF, ZF and PF from the result.
+macro_rules! define_shift (
+    (
+        $name:ident,
+        $size:ident,
+        $bits:expr,
+        $op:expr,
+        $sets_result_flags:expr
+    ) => {
+        pub fn $name(left: $size, right: $size, flags: Flags) -> ($size, Flags) {
+            if right == 0 {
+                return (left, flags);
+            }
+
+            let (result, cf, of) = byteutils::shift($op,
+                                                    left.to_u16().unwrap(),
+                                                    right.to_u16().unwrap(),
+                                                    $bits,
+                                                    flags.carry,
+                                                    flags.overflow);
+            let mut flags = flags;
+            flags.carry = cf;
+            flags.overflow = of;
+            if $sets_result_flags {
+                flags.sign = (result >> ($bits - 1)) == 1;
+                flags.zero = result == 0;
+                flags.aux_carry = false;
+                flags.parity = byteutils::parity(result);
+            }
+            (result as $size, flags)
+        }
+    }
+);
+
+define_shift!(rol8,   Byte, 8, ShiftOp::Rol,   false);
+define_shift!(ror8,   Byte, 8, ShiftOp::Ror,   false);
+define_shift!(rcl8,   Byte, 8, ShiftOp::Rcl,   false);
+define_shift!(rcr8,   Byte, 8, ShiftOp::Rcr,   false);
+define_shift!(shl8,   Byte, 8, ShiftOp::Shl,   true);
+define_shift!(shr8,   Byte, 8, ShiftOp::Shr,   true);
+define_shift!(setmo8, Byte, 8, ShiftOp::Setmo, true);
+define_shift!(sar8,   Byte, 8, ShiftOp::Sar,   true);
+
+define_shift!(rol16,   Word, 16, ShiftOp::Rol,   false);
+define_shift!(ror16,   Word, 16, ShiftOp::Ror,   false);
+define_shift!(rcl16,   Word, 16, ShiftOp::Rcl,   false);
+define_shift!(rcr16,   Word, 16, ShiftOp::Rcr,   false);
+define_shift!(shl16,   Word, 16, ShiftOp::Shl,   true);
+define_shift!(shr16,   Word, 16, ShiftOp::Shr,   true);
+define_shift!(setmo16, Word, 16, ShiftOp::Setmo, true);
+define_shift!(sar16,   Word, 16, ShiftOp::Sar,   true);
+
 pub fn noop8(_: Byte, right: Byte, flags: Flags) -> (Byte, Flags) {
     (right, flags)
 }